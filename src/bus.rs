@@ -1,37 +1,529 @@
 use cartridge::Cartridge;
+use cpu::{Memory, Z80Bus};
+use glasses::Eye;
+use input::{ControllerPortDevice, Joypad, Pins, Port, PortInput, Screen};
+use psg::Psg;
+use region::{Region, TvStandard};
+use savestate::{StateReader, StateWriter};
+use scheduler::Scheduler;
+use system::System;
+use vdp::Vdp;
+use watchpoints::{Access, Watchpoints};
+
+bitflags! {
+    // Port 0x3F, controls the direction and output level of the TR and TH
+    // pins on both controller ports
+    struct IoControl: u8 {
+        const A_TR_INPUT = 0b00000001;
+        const A_TH_INPUT = 0b00000010;
+        const B_TR_INPUT = 0b00000100;
+        const B_TH_INPUT = 0b00001000;
+        const A_TR_LEVEL = 0b00010000;
+        const A_TH_LEVEL = 0b00100000;
+        const B_TR_LEVEL = 0b01000000;
+        const B_TH_LEVEL = 0b10000000;
+    }
+}
+
+bitflags! {
+    // Port 0x3E, each bit disables part of the system when set
+    struct MemoryControl: u8 {
+        const IO_DISABLE = 0b00000100;
+        const BIOS_DISABLE = 0b00001000;
+        const RAM_DISABLE = 0b00010000;
+        const CARD_DISABLE = 0b00100000;
+        const CARTRIDGE_DISABLE = 0b01000000;
+        const EXPANSION_DISABLE = 0b10000000;
+    }
+}
+
+// Port 0x3E at power on, only the BIOS, RAM and I/O are enabled
+const POWER_ON_MEMORY_CONTROL: u8 = 0xe3;
+
+// The places media can be plugged in, each is enabled through port 0x3E
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Bios,
+    Cartridge,
+    Card,
+    Expansion,
+}
+
+impl Slot {
+    fn disable_bit(&self) -> MemoryControl {
+        match *self {
+            Slot::Bios => MemoryControl::BIOS_DISABLE,
+            Slot::Cartridge => MemoryControl::CARTRIDGE_DISABLE,
+            Slot::Card => MemoryControl::CARD_DISABLE,
+            Slot::Expansion => MemoryControl::EXPANSION_DISABLE,
+        }
+    }
+}
+
+const SLOTS: [Slot; 4] = [Slot::Bios, Slot::Cartridge, Slot::Card, Slot::Expansion];
+
+// Save state chunks for the media in each slot and the controller ports
+const SLOT_CHUNKS: [&[u8; 4]; 4] = [b"SLT0", b"SLT1", b"SLT2", b"SLT3"];
+const PORT_CHUNKS: [&[u8; 4]; 2] = [b"PRTA", b"PRTB"];
 
 pub struct Bus {
-    cart: Cartridge,
+    slots: [Option<Cartridge>; 4],
+    memory_control: MemoryControl,
     ram: Box<[u8]>,
+    system: System,
+    region: Region,
+    vdp: Vdp,
+    psg: Psg,
+    ports: [Box<dyn ControllerPortDevice>; 2],
+    io_control: IoControl,
+    // Which lens of the 3-D glasses is open, None until the game writes to them
+    shutter: Option<Eye>,
+    scheduler: Scheduler,
+    // The VDP's interrupt output as of the last sync
+    irq: bool,
+    // Whether the FM sound unit is fitted, and its audio control register
+    fm: bool,
+    audio_control: u8,
+    // Text homebrew and test ROMs write to the SDSC debug console
+    console: Vec<u8>,
+    watchpoints: Watchpoints,
 }
 
 impl Bus {
-    pub fn new(cart: Cartridge) -> Self {
+    // Creates a bus with every slot empty, as it is at power on
+    pub fn new(system: System, region: Region, tv_standard: TvStandard, sample_rate: u32) -> Self {
         Bus {
-            cart: cart,
+            slots: [None, None, None, None],
+            memory_control: MemoryControl::from_bits_truncate(POWER_ON_MEMORY_CONTROL),
             ram: vec![0; 0x2000].into_boxed_slice(),
+            system,
+            region,
+            vdp: Vdp::new(tv_standard),
+            psg: Psg::new(tv_standard.cpu_clock(), sample_rate),
+            ports: [Box::new(Joypad::default()), Box::new(Joypad::default())],
+            io_control: IoControl::all(),
+            shutter: None,
+            scheduler: Scheduler::new(),
+            irq: false,
+            fm: false,
+            audio_control: 0,
+            console: Vec::new(),
+            watchpoints: Watchpoints::default(),
+        }
+    }
+
+    // Fits the FM sound unit, built into Japanese Master Systems and an add-on
+    // for the Mark III. Only its audio control port is there for games to
+    // detect it, the YM2413 itself isn't emulated so its music is silent.
+    pub fn set_fm(&mut self, fitted: bool) {
+        self.fm = fitted;
+    }
+
+    pub fn console(&self) -> &[u8] {
+        &self.console
+    }
+
+    pub fn clear_console(&mut self) {
+        self.console.clear();
+    }
+
+    pub fn connect(&mut self, port: Port, device: Box<dyn ControllerPortDevice>) {
+        self.ports[port as usize] = device;
+        self.update_outputs();
+    }
+
+    pub fn set_input(&mut self, port: Port, input: &PortInput) {
+        self.ports[port as usize].set_input(input);
+    }
+
+    // Plugs media into a slot, or empties it with None, returning whatever
+    // was there before. Can be used while the system is running.
+    pub fn insert(&mut self, slot: Slot, media: Option<Cartridge>) -> Option<Cartridge> {
+        ::std::mem::replace(&mut self.slots[slot as usize], media)
+    }
+
+    pub fn media(&self, slot: Slot) -> Option<&Cartridge> {
+        self.slots[slot as usize].as_ref()
+    }
+
+    pub fn system(&self) -> System {
+        self.system
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn has_bios(&self) -> bool {
+        self.slots[Slot::Bios as usize].is_some()
+    }
+
+    // Sets up the memory control port as the BIOS leaves it once it has
+    // found something to boot, enabling the first slot with media in it
+    pub fn skip_bios(&mut self) {
+        let boot = [Slot::Cartridge, Slot::Card, Slot::Expansion].iter()
+            .cloned()
+            .find(|&slot| self.slots[slot as usize].is_some())
+            .unwrap_or(Slot::Cartridge);
+
+        let disabled = SLOTS.iter()
+            .filter(|&&slot| slot != boot)
+            .fold(MemoryControl::empty(), |bits, slot| bits | slot.disable_bit());
+        let memory_control = disabled.bits() | 0x03;
+
+        self.memory_control = MemoryControl::from_bits_truncate(memory_control);
+
+        // The BIOS keeps a copy of the last value written to port 0x3E
+        self.ram[0] = memory_control;
+    }
+
+    pub fn vdp(&self) -> &Vdp {
+        &self.vdp
+    }
+
+    pub fn vdp_mut(&mut self) -> &mut Vdp {
+        &mut self.vdp
+    }
+
+    pub fn psg(&self) -> &Psg {
+        &self.psg
+    }
+
+    pub fn psg_mut(&mut self) -> &mut Psg {
+        &mut self.psg
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    pub fn shutter(&self) -> Option<Eye> {
+        self.shutter
+    }
+
+    // Writes a chunk for each part of the machine on the bus. The media
+    // itself isn't saved, only the mapper state, and the watchpoints belong
+    // to the debugger rather than the machine.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.chunk(b"BUS ", |w| {
+            w.u8(self.memory_control.bits());
+            w.u8(self.io_control.bits());
+            w.bytes(&self.ram);
+            w.u8(match self.shutter {
+                None => 0,
+                Some(Eye::Left) => 1,
+                Some(Eye::Right) => 2,
+            });
+            w.bool(self.irq);
+            self.scheduler.save_state(w);
+            w.u8(self.audio_control);
+        });
+        w.chunk(b"VDP ", |w| self.vdp.save_state(w));
+        w.chunk(b"PSG ", |w| self.psg.save_state(w));
+
+        for (i, media) in self.slots.iter().enumerate() {
+            if let Some(ref media) = *media {
+                w.chunk(SLOT_CHUNKS[i], |w| media.save_state(w));
+            }
+        }
+
+        for (i, device) in self.ports.iter().enumerate() {
+            w.chunk(PORT_CHUNKS[i], |w| w.raw(&device.save_state()));
+        }
+    }
+
+    // Restores one of the chunks written by save_state, ignoring any others
+    pub fn load_chunk(&mut self, tag: &[u8; 4], r: &mut StateReader) {
+        match tag {
+            b"BUS " => {
+                self.memory_control = MemoryControl::from_bits_truncate(r.u8());
+                self.io_control = IoControl::from_bits_truncate(r.u8());
+                r.bytes_into(&mut self.ram);
+                self.shutter = match r.u8() {
+                    1 => Some(Eye::Left),
+                    2 => Some(Eye::Right),
+                    _ => None,
+                };
+                self.irq = r.bool();
+                self.scheduler.load_state(r);
+                self.audio_control = r.u8();
+            }
+            b"VDP " => self.vdp.load_state(r),
+            b"PSG " => self.psg.load_state(r),
+            _ => {
+                if let Some(i) = SLOT_CHUNKS.iter().position(|chunk| *chunk == tag) {
+                    if let Some(ref mut media) = self.slots[i] {
+                        media.load_state(r);
+                    }
+                } else if let Some(i) = PORT_CHUNKS.iter().position(|chunk| *chunk == tag) {
+                    self.ports[i].load_state(r.rest());
+                }
+            }
+        }
+    }
+
+    // Runs everything besides the CPU up to the master clock
+    pub fn sync(&mut self) {
+        let cycles = self.scheduler.pending();
+
+        if cycles > 0 {
+            self.vdp.tick(cycles);
+            self.psg.tick(cycles);
+
+            for (i, device) in self.ports.iter_mut().enumerate() {
+                let latched = device.tick(&Screen::new(&self.vdp), cycles);
+
+                if let Some(x) = latched {
+                    let th_input = if i == 0 { IoControl::A_TH_INPUT } else { IoControl::B_TH_INPUT };
+
+                    if self.io_control.contains(th_input) {
+                        self.vdp.latch_h_counter(x);
+                    }
+                }
+            }
         }
+
+        self.scheduler.synced(self.vdp.cycles_to_next_line());
+        self.irq = self.vdp.interrupt_pending();
     }
 
     pub fn read8(&self, addr: u16) -> u8 {
         match addr {
-            0...0xbfff => self.cart.read_u8(addr),
-            0xc000...0xdfff => self.ram[addr as usize - 0xc000],
-            _ => panic!("Read from unrecognised address 0x{:04x}", addr),
+            0..=0xbfff => self.read_media(addr),
+            0xc000..=0xffff => self.read_ram(addr),
         }
     }
 
     pub fn write8(&mut self, addr: u16, val: u8) {
         match addr {
-            0xc000...0xdfff => self.ram[addr as usize - 0xc000] = val,
-            _ => panic!("Write to unrecognised address 0x{:04x}", addr),
+            0..=0xbfff => self.write_media(addr, val),
+            0xfff8..=0xfffb => {
+                // The 3-D glasses' shutters, bit 0 opens the right lens
+                self.shutter = Some(if val & 1 != 0 { Eye::Right } else { Eye::Left });
+                self.write_ram(addr, val);
+            }
+            0xfffc..=0xffff => {
+                // Mapper registers, which also write through to RAM
+                self.write_media(addr, val);
+                self.write_ram(addr, val);
+            }
+            0xc000..=0xfff7 => self.write_ram(addr, val),
         }
     }
 
-    pub fn out8(&mut self, addr: u8, val: u8) {
+    // Reads from whichever slots are enabled through port 0x3E. With more
+    // than one enabled the ROMs fight over the data bus, and a zero bit wins.
+    fn read_media(&self, addr: u16) -> u8 {
+        SLOTS.iter()
+            .filter(|slot| !self.memory_control.contains(slot.disable_bit()))
+            .filter_map(|&slot| self.slots[slot as usize].as_ref())
+            .fold(0xff, |val, media| val & media.read_u8(addr))
+    }
+
+    // ROM bank of the first enabled slot at addr, None for RAM
+    pub fn rom_bank(&self, addr: u16) -> Option<usize> {
+        if addr >= 0xc000 {
+            return None;
+        }
+
+        SLOTS.iter()
+            .filter(|slot| !self.memory_control.contains(slot.disable_bit()))
+            .filter_map(|&slot| self.slots[slot as usize].as_ref())
+            .next()?
+            .bank(addr)
+    }
+
+    fn write_media(&mut self, addr: u16, val: u8) {
+        for &slot in SLOTS.iter() {
+            if self.memory_control.contains(slot.disable_bit()) {
+                continue;
+            }
+
+            if let Some(ref mut media) = self.slots[slot as usize] {
+                media.write_u8(addr, val);
+            }
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if self.memory_control.contains(MemoryControl::RAM_DISABLE) {
+            0xff
+        } else {
+            self.ram[addr as usize & 0x1fff]
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if !self.memory_control.contains(MemoryControl::RAM_DISABLE) {
+            self.ram[addr as usize & 0x1fff] = val;
+        }
+    }
+
+    fn read_port(&mut self, addr: u8) -> u8 {
+        match addr {
+            0x40..=0x7f if addr & 1 == 0 => self.vdp.v_counter(),
+            0x40..=0x7f => self.vdp.h_counter(),
+            0x80..=0xbf if addr & 1 == 0 => self.vdp.read_data(),
+            0x80..=0xbf => self.vdp.read_control(),
+            // Games detect the FM unit by reading back what they wrote here
+            0xf2 if self.fm => self.audio_control,
+            0xc0..=0xff if self.memory_control.contains(MemoryControl::IO_DISABLE) => 0xff,
+            0xc0..=0xff if addr & 1 == 0 => {
+                let a = self.port_pins(Port::A);
+                let b = self.port_pins(Port::B);
+
+                (a.bits() & 0x3f) | ((b.bits() & 0x03) << 6)
+            }
+            0xc0..=0xff => {
+                let a = self.port_pins(Port::A);
+                let b = self.port_pins(Port::B);
+
+                // Bit 4 is the reset button and bit 5 the cartridge CONT pin
+                let mut val = ((b.bits() >> 2) & 0x0f) | 0x30;
+
+                if a.contains(Pins::TH) {
+                    val |= 0x40;
+                }
+                if b.contains(Pins::TH) {
+                    val |= 0x80;
+                }
+
+                val
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_port(&mut self, addr: u8, val: u8) {
         match addr {
-            0xfd => print!("{}", val as char),
-            _ => println!("Write to port {:02x} = {:02x}", addr, val),
+            0xfd => self.console.push(val),
+            0x00..=0x3f if addr & 1 == 0 => {
+                self.memory_control = MemoryControl::from_bits_truncate(val);
+            }
+            0x00..=0x3f if addr & 1 == 1 => {
+                self.io_control = IoControl::from_bits_truncate(val);
+                self.update_outputs();
+            }
+            0x40..=0x7f => self.psg.write(val),
+            0x80..=0xbf if addr & 1 == 0 => self.vdp.write_data(val),
+            0x80..=0xbf => self.vdp.write_control(val),
+            // The YM2413's address and data ports, which aren't emulated
+            0xf0 | 0xf1 if self.fm => {}
+            0xf2 if self.fm => self.audio_control = val & 0x03,
+            // Nothing else is listening
+            _ => {}
+        }
+    }
+
+    // Tells each device what levels the console is driving TR and TH to
+    fn update_outputs(&mut self) {
+        let output = |input, level| {
+            if self.io_control.contains(input) {
+                None
+            } else {
+                Some(self.io_control.contains(level))
+            }
+        };
+
+        let a_tr = output(IoControl::A_TR_INPUT, IoControl::A_TR_LEVEL);
+        let a_th = output(IoControl::A_TH_INPUT, IoControl::A_TH_LEVEL);
+        let b_tr = output(IoControl::B_TR_INPUT, IoControl::B_TR_LEVEL);
+        let b_th = output(IoControl::B_TH_INPUT, IoControl::B_TH_LEVEL);
+
+        self.ports[0].write(a_tr, a_th);
+        self.ports[1].write(b_tr, b_th);
+    }
+
+    // Pin levels seen on a controller port, TR and TH read back their output
+    // level when configured as outputs. Japanese consoles read TH back
+    // inverted, which games use to detect the region.
+    fn port_pins(&self, port: Port) -> Pins {
+        let (tr_input, th_input, tr_level, th_level) = match port {
+            Port::A => (IoControl::A_TR_INPUT, IoControl::A_TH_INPUT, IoControl::A_TR_LEVEL, IoControl::A_TH_LEVEL),
+            Port::B => (IoControl::B_TR_INPUT, IoControl::B_TH_INPUT, IoControl::B_TR_LEVEL, IoControl::B_TH_LEVEL),
+        };
+
+        let mut pins = self.ports[port as usize].read();
+
+        if !self.io_control.contains(tr_input) {
+            pins.set(Pins::TR, self.io_control.contains(tr_level));
         }
+        if !self.io_control.contains(th_input) {
+            let level = self.io_control.contains(th_level);
+            pins.set(Pins::TH, level != (self.region == Region::Japan));
+        }
+
+        pins
+    }
+}
+
+// Accesses made by the CPU, which are checked against the watchpoints
+impl Z80Bus for Bus {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let val = Bus::read8(self, addr);
+        self.watchpoints.check(Access::Read, addr, val);
+
+        val
+    }
+
+    // Instruction fetches don't trip read watchpoints
+    fn fetch8(&mut self, addr: u16) -> u8 {
+        Bus::read8(self, addr)
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.watchpoints.check(Access::Write, addr, val);
+        Bus::write8(self, addr, val);
+    }
+
+    // Port accesses bring everything up to date first, so the VDP and
+    // controllers see them at the right point in the frame. The SMS only
+    // decodes the low byte of the address.
+    fn in8(&mut self, port: u16) -> u8 {
+        self.sync();
+
+        let val = self.read_port(port as u8);
+        self.irq = self.vdp.interrupt_pending();
+        self.watchpoints.check(Access::In, port, val);
+
+        val
+    }
+
+    fn out8(&mut self, port: u16, val: u8) {
+        self.sync();
+        self.watchpoints.check(Access::Out, port, val);
+
+        self.write_port(port as u8, val);
+        self.irq = self.vdp.interrupt_pending();
+    }
+
+    // Moves the master clock on to the given T-state. Everything besides the
+    // CPU only runs once it is needed, so this is cheap until the VDP reaches
+    // its next line.
+    fn advance_to(&mut self, now: u64) {
+        self.scheduler.advance_to(now);
+
+        if self.scheduler.due() {
+            self.sync();
+        }
+    }
+
+    // The CPU's INT line, low while the VDP is requesting an interrupt
+    fn interrupt_line(&self) -> bool {
+        self.irq
+    }
+}
+
+impl Memory for Bus {
+    fn peek8(&self, addr: u16) -> u8 {
+        self.read8(addr)
+    }
+
+    fn bank(&self, addr: u16) -> Option<usize> {
+        self.rom_bank(addr)
     }
 }
@@ -0,0 +1,148 @@
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+// File extensions taken to be ROMs when searching an archive
+const ROM_EXTENSIONS: [&str; 6] = ["sms", "gg", "sg", "sc", "bin", "rom"];
+
+// Unpacks a ROM if the file is a zip or gzip archive. Returns the ROM along
+// with the name of the file it came from, which for an archive is the name of
+// the entry inside it. From a zip the named entry is used if there is one,
+// otherwise the first entry that looks like a ROM.
+pub fn extract(buffer: Vec<u8>, file_name: &Path, entry: Option<&str>) -> io::Result<(Vec<u8>, String)> {
+    if buffer.starts_with(ZIP_MAGIC) {
+        extract_zip(buffer, entry)
+    } else if buffer.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(&buffer[..]).read_to_end(&mut rom)?;
+
+        // game.sms.gz holds game.sms
+        let name = file_name.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+
+        Ok((rom, name))
+    } else {
+        Ok((buffer, file_name.to_string_lossy().into_owned()))
+    }
+}
+
+fn extract_zip(buffer: Vec<u8>, entry: Option<&str>) -> io::Result<(Vec<u8>, String)> {
+    let mut archive = ZipArchive::new(Cursor::new(buffer)).map_err(zip_error)?;
+
+    if let Some(name) = entry {
+        return read_entry(archive.by_name(name).map_err(zip_error)?);
+    }
+
+    // Searched by index so the choice follows the order of the archive
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(zip_error)?;
+
+        if is_rom_name(file.name()) {
+            return read_entry(file);
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "No ROM found in zip archive"))
+}
+
+fn read_entry(mut file: ZipFile) -> io::Result<(Vec<u8>, String)> {
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)?;
+
+    Ok((rom, file.name().to_owned()))
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| ROM_EXTENSIONS.iter().any(|rom| extension.eq_ignore_ascii_case(rom)))
+        .unwrap_or(false)
+}
+
+fn zip_error(err: ZipError) -> io::Error {
+    match err {
+        ZipError::Io(err) => err,
+        ZipError::FileNotFound => io::Error::new(io::ErrorKind::NotFound, "Entry not found in zip archive"),
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    use super::*;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        for &(name, data) in entries {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(data).unwrap();
+
+        gzip.finish().unwrap()
+    }
+
+    #[test]
+    fn plain_roms_are_left_alone() {
+        let (rom, name) = extract(b"rom".to_vec(), Path::new("game.sms"), None).unwrap();
+
+        assert_eq!(rom, b"rom");
+        assert_eq!(name, "game.sms");
+    }
+
+    #[test]
+    fn gzip_is_unpacked() {
+        let (rom, name) = extract(gzip(b"rom"), Path::new("dir/game.gg.gz"), None).unwrap();
+
+        assert_eq!(rom, b"rom");
+        assert_eq!(name, "game.gg");
+    }
+
+    #[test]
+    fn zip_picks_the_first_rom() {
+        let buffer = zip(&[("readme.txt", b"text"), ("game.GG", b"first"), ("other.sms", b"second")]);
+        let (rom, name) = extract(buffer, Path::new("game.zip"), None).unwrap();
+
+        assert_eq!(rom, b"first");
+        assert_eq!(name, "game.GG");
+    }
+
+    #[test]
+    fn zip_entry_by_name() {
+        let buffer = zip(&[("game.sms", b"first"), ("readme.txt", b"text")]);
+
+        let (rom, name) = extract(buffer.clone(), Path::new("game.zip"), Some("readme.txt")).unwrap();
+        assert_eq!(rom, b"text");
+        assert_eq!(name, "readme.txt");
+
+        let err = extract(buffer, Path::new("game.zip"), Some("missing.sms")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn zip_without_a_rom() {
+        let err = extract(zip(&[("readme.txt", b"text")]), Path::new("game.zip"), None).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
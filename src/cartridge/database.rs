@@ -0,0 +1,166 @@
+use std::iter;
+
+use crc32fast;
+use sha1_smol::Sha1;
+
+use input::DeviceKind;
+use region::{Region, TvStandard};
+use super::mapper::MapperType;
+
+const DATABASE: &str = include_str!("games.txt");
+
+// Settings for a known ROM, any field left as None wasn't given in the database
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseEntry {
+    pub name: Option<String>,
+    pub mapper: Option<MapperType>,
+    pub region: Option<Region>,
+    pub tv_standard: Option<TvStandard>,
+    // Peripheral the game needs in port A
+    pub peripheral: Option<DeviceKind>,
+    // A game that plays its music on the FM sound unit when one is fitted
+    pub fm: Option<bool>,
+    // A Game Gear game that runs in the SMS compatibility mode
+    pub gg_sms_mode: Option<bool>,
+    // A game made for the SegaScope 3-D glasses
+    pub glasses: Option<bool>,
+}
+
+// Finds the database entry for a ROM by its CRC32, and SHA-1 where the entry
+// gives one
+pub fn lookup(rom: &[u8]) -> Option<DatabaseEntry> {
+    find(crc32fast::hash(rom), rom)
+}
+
+fn find(crc: u32, rom: &[u8]) -> Option<DatabaseEntry> {
+    let crc = format!("{:08x}", crc);
+    let mut sha1 = None;
+
+    // The entry being read, if its CRC matched, and whether its SHA-1 matches
+    let mut current: Option<(DatabaseEntry, bool)> = None;
+
+    // A trailing empty section finishes off the last entry
+    for line in DATABASE.lines().map(str::trim).chain(iter::once("[]")) {
+        if line.starts_with('[') {
+            if let Some((entry, true)) = current.take() {
+                return Some(entry);
+            }
+
+            let key = line.trim_start_matches('[').trim_end_matches(']');
+            if key.eq_ignore_ascii_case(&crc) {
+                current = Some((DatabaseEntry::default(), true));
+            }
+
+            continue;
+        }
+
+        let (entry, sha1_matches) = match current {
+            Some((ref mut entry, ref mut sha1_matches)) => (entry, sha1_matches),
+            None => continue,
+        };
+        let (key, value) = match parse_line(line) {
+            Some(kv) => kv,
+            None => continue,
+        };
+
+        match key {
+            "name" => entry.name = Some(value.to_owned()),
+            "sha1" => {
+                let hash = sha1.get_or_insert_with(|| Sha1::from(rom).digest().to_string());
+                *sha1_matches = value.eq_ignore_ascii_case(hash);
+            }
+            "mapper" => entry.mapper = parse_mapper(value),
+            "region" => entry.region = parse_region(value),
+            "tv" => entry.tv_standard = parse_tv_standard(value),
+            "peripheral" => entry.peripheral = parse_peripheral(value),
+            "fm" => entry.fm = value.parse().ok(),
+            "gg-sms-mode" => entry.gg_sms_mode = value.parse().ok(),
+            "glasses" => entry.glasses = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+// Splits a `key = value` line, ignoring blank lines and comments
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next()?.trim();
+    let value = parts.next()?.trim().trim_matches('"');
+
+    Some((key, value))
+}
+
+fn parse_mapper(value: &str) -> Option<MapperType> {
+    match value {
+        "none" => Some(MapperType::None),
+        "sega" => Some(MapperType::Sega),
+        "codemasters" => Some(MapperType::Codemasters),
+        "korean" => Some(MapperType::Korean),
+        _ => None,
+    }
+}
+
+fn parse_region(value: &str) -> Option<Region> {
+    match value {
+        "japan" => Some(Region::Japan),
+        "export" => Some(Region::Export),
+        _ => None,
+    }
+}
+
+fn parse_tv_standard(value: &str) -> Option<TvStandard> {
+    match value {
+        "ntsc" => Some(TvStandard::Ntsc),
+        "pal" => Some(TvStandard::Pal),
+        _ => None,
+    }
+}
+
+fn parse_peripheral(value: &str) -> Option<DeviceKind> {
+    match value {
+        "joypad" => Some(DeviceKind::Joypad),
+        "light-phaser" => Some(DeviceKind::LightPhaser),
+        "paddle" => Some(DeviceKind::Paddle),
+        "sports-pad" => Some(DeviceKind::SportsPad),
+        "mega-drive-pad" => Some(DeviceKind::MegaDrivePad),
+        "mega-drive-pad-6" => Some(DeviceKind::MegaDrivePad6),
+        "none" => Some(DeviceKind::Unconnected),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(crc: u32) -> DatabaseEntry {
+        super::find(crc, &[]).unwrap()
+    }
+
+    #[test]
+    fn overrides() {
+        assert_eq!(find(0xa577ce46).mapper, Some(MapperType::Codemasters));
+        assert_eq!(find(0xa577ce46).tv_standard, Some(TvStandard::Pal));
+        assert_eq!(find(0x18fb98a3).mapper, Some(MapperType::Korean));
+        assert_eq!(find(0x6bd5c2bf).glasses, Some(true));
+        assert_eq!(find(0x49e9718b).peripheral, Some(DeviceKind::LightPhaser));
+        assert_eq!(find(0xf9dbb533).peripheral, Some(DeviceKind::Paddle));
+        assert_eq!(find(0xf9dbb533).region, Some(Region::Japan));
+        assert_eq!(find(0x946b8c4a).peripheral, Some(DeviceKind::SportsPad));
+        assert_eq!(find(0xf7c524f6).gg_sms_mode, Some(true));
+        assert_eq!(find(0x6605d36a).fm, Some(true));
+        assert_eq!(find(0x6605d36a).name.as_deref(), Some("Phantasy Star (Japan)"));
+    }
+
+    #[test]
+    fn unknown_roms_have_no_entry() {
+        assert!(super::find(0x12345678, &[]).is_none());
+        assert!(lookup(&[0; 0x8000]).is_none());
+    }
+}
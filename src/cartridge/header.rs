@@ -0,0 +1,247 @@
+use region::{Region, TvStandard};
+
+// Offsets the header may be found at, in the order the export BIOS checks them
+const HEADER_OFFSETS: [usize; 3] = [0x7ff0, 0x3ff0, 0x1ff0];
+const HEADER_SIGNATURE: &[u8] = b"TMR SEGA";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionCode {
+    SmsJapan,
+    SmsExport,
+    GameGearJapan,
+    GameGearExport,
+    GameGearInternational,
+    Unknown(u8),
+}
+
+impl RegionCode {
+    fn from_nibble(code: u8) -> RegionCode {
+        match code {
+            3 => RegionCode::SmsJapan,
+            4 => RegionCode::SmsExport,
+            5 => RegionCode::GameGearJapan,
+            6 => RegionCode::GameGearExport,
+            7 => RegionCode::GameGearInternational,
+            _ => RegionCode::Unknown(code),
+        }
+    }
+
+    pub fn region(&self) -> Option<Region> {
+        match *self {
+            RegionCode::SmsJapan | RegionCode::GameGearJapan => Some(Region::Japan),
+            RegionCode::SmsExport | RegionCode::GameGearExport |
+                RegionCode::GameGearInternational => Some(Region::Export),
+            RegionCode::Unknown(_) => None,
+        }
+    }
+
+    // Japan only ever had NTSC consoles, and the Game Gear runs at NTSC timing
+    // everywhere. Export cartridges were sold in both NTSC and PAL markets,
+    // so the header can't tell which they were made for.
+    pub fn tv_standard(&self) -> Option<TvStandard> {
+        match *self {
+            RegionCode::SmsJapan | RegionCode::GameGearJapan | RegionCode::GameGearExport |
+                RegionCode::GameGearInternational => Some(TvStandard::Ntsc),
+            RegionCode::SmsExport | RegionCode::Unknown(_) => None,
+        }
+    }
+}
+
+// The contents of the Sega header at the end of the first 8, 16 or 32KB
+#[derive(Debug, Clone)]
+pub struct CartridgeInfo {
+    pub header_offset: usize,
+    pub checksum: u16,
+    // Checksum of the ROM calculated over the declared size
+    pub calculated_checksum: u16,
+    pub product_code: u32,
+    pub version: u8,
+    pub region_code: RegionCode,
+    // Declared ROM size in bytes, None for an invalid size code
+    pub rom_size: Option<usize>,
+}
+
+impl CartridgeInfo {
+    pub fn parse(rom: &[u8]) -> Option<CartridgeInfo> {
+        let header_offset = HEADER_OFFSETS.iter()
+            .cloned()
+            .find(|&offset| rom.get(offset..offset + 8) == Some(HEADER_SIGNATURE))?;
+        let header = rom.get(header_offset..header_offset + 0x10)?;
+
+        // The product code is BCD, with any digits beyond the fourth held in
+        // the top nibble of byte 0xE
+        let product_code = bcd(header[0xc]) as u32 + bcd(header[0xd]) as u32 * 100 +
+            (header[0xe] >> 4) as u32 * 10000;
+
+        let rom_size = rom_size(header[0xf] & 0x0f);
+
+        Some(CartridgeInfo {
+            header_offset,
+            checksum: header[0xa] as u16 | ((header[0xb] as u16) << 8),
+            calculated_checksum: checksum(rom, rom_size.unwrap_or(0)),
+            product_code,
+            version: header[0xe] & 0x0f,
+            region_code: RegionCode::from_nibble(header[0xf] >> 4),
+            rom_size,
+        })
+    }
+
+    // Whether the checksum matches, as verified by the export BIOS before it
+    // will boot a cartridge
+    pub fn checksum_valid(&self) -> bool {
+        self.rom_size.is_some() && self.checksum == self.calculated_checksum
+    }
+
+    pub fn region(&self) -> Option<Region> {
+        self.region_code.region()
+    }
+
+    pub fn tv_standard(&self) -> Option<TvStandard> {
+        self.region_code.tv_standard()
+    }
+}
+
+fn bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0x0f)
+}
+
+fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        0xa => Some(0x2000),
+        0xb => Some(0x4000),
+        0xc => Some(0x8000),
+        0xd => Some(0xc000),
+        0xe => Some(0x10000),
+        0xf => Some(0x20000),
+        0x0 => Some(0x40000),
+        0x1 => Some(0x80000),
+        0x2 => Some(0x100000),
+        _ => None,
+    }
+}
+
+// Sums every byte up to the declared size, skipping the 16 byte header
+fn checksum(rom: &[u8], size: usize) -> u16 {
+    let end = size.min(rom.len());
+    let header = match size {
+        0x2000 => 0x1ff0,
+        0x4000 => 0x3ff0,
+        _ => 0x7ff0,
+    };
+
+    rom[..end].iter()
+        .enumerate()
+        .filter(|&(i, _)| i < header || i >= header + 0x10)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TestMachine;
+
+    // The test machine's program sums to 0x572
+    const PROGRAM_SUM: u16 = 0x572;
+
+    // A header with product code 21234, version 5 and the given checksum,
+    // region code and size code
+    fn header(checksum: u16, region_size: u8) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[..8].copy_from_slice(HEADER_SIGNATURE);
+        header[0xa] = checksum as u8;
+        header[0xb] = (checksum >> 8) as u8;
+        header[0xc] = 0x34;
+        header[0xd] = 0x12;
+        header[0xe] = 0x25;
+        header[0xf] = region_size;
+
+        header
+    }
+
+    #[test]
+    fn fields() {
+        let rom = TestMachine::default().with_bytes(0x7ff0, &header(0, 0x4c)).into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+
+        assert_eq!(info.header_offset, 0x7ff0);
+        assert_eq!(info.product_code, 21234);
+        assert_eq!(info.version, 5);
+        assert_eq!(info.region_code, RegionCode::SmsExport);
+        assert_eq!(info.rom_size, Some(0x8000));
+    }
+
+    #[test]
+    fn checksum_skips_the_header_and_stops_at_the_declared_size() {
+        // Counted, in the declared 32KB
+        let rom = TestMachine::default()
+            .with_bytes(0x7000, &[0x10])
+            .with_bytes(0x7ff0, &header(PROGRAM_SUM + 0x10, 0x4c))
+            .into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+
+        assert_eq!(info.calculated_checksum, PROGRAM_SUM + 0x10);
+        assert!(info.checksum_valid());
+
+        // Beyond a declared 16KB, so left out
+        let rom = TestMachine::default()
+            .with_bytes(0x7000, &[0x10])
+            .with_bytes(0x7ff0, &header(PROGRAM_SUM, 0x4b))
+            .into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+
+        assert_eq!(info.rom_size, Some(0x4000));
+        assert_eq!(info.calculated_checksum, PROGRAM_SUM);
+        assert!(info.checksum_valid());
+
+        // A wrong checksum, and a size code that isn't one
+        let rom = TestMachine::default().with_bytes(0x7ff0, &header(PROGRAM_SUM + 1, 0x4c)).into_rom();
+        assert!(!CartridgeInfo::parse(&rom).unwrap().checksum_valid());
+
+        let rom = TestMachine::default().with_bytes(0x7ff0, &header(PROGRAM_SUM, 0x43)).into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+        assert_eq!(info.rom_size, None);
+        assert!(!info.checksum_valid());
+    }
+
+    #[test]
+    fn header_offsets_are_searched_in_order() {
+        let rom = TestMachine::default()
+            .with_bytes(0x3ff0, &header(0, 0x3b))
+            .with_bytes(0x7ff0, &header(0, 0x4c))
+            .into_rom();
+        assert_eq!(CartridgeInfo::parse(&rom).unwrap().header_offset, 0x7ff0);
+
+        let rom = TestMachine::default().with_size(0x4000).with_bytes(0x3ff0, &header(PROGRAM_SUM, 0x3b)).into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+        assert_eq!(info.header_offset, 0x3ff0);
+        assert!(info.checksum_valid());
+
+        let rom = TestMachine::default().with_size(0x2000).with_bytes(0x1ff0, &header(PROGRAM_SUM, 0x3a)).into_rom();
+        let info = CartridgeInfo::parse(&rom).unwrap();
+        assert_eq!(info.header_offset, 0x1ff0);
+        assert!(info.checksum_valid());
+
+        assert!(CartridgeInfo::parse(&TestMachine::default().into_rom()).is_none());
+    }
+
+    #[test]
+    fn region_codes() {
+        let codes = [
+            (3, RegionCode::SmsJapan, Some(Region::Japan), Some(TvStandard::Ntsc)),
+            (4, RegionCode::SmsExport, Some(Region::Export), None),
+            (5, RegionCode::GameGearJapan, Some(Region::Japan), Some(TvStandard::Ntsc)),
+            (6, RegionCode::GameGearExport, Some(Region::Export), Some(TvStandard::Ntsc)),
+            (7, RegionCode::GameGearInternational, Some(Region::Export), Some(TvStandard::Ntsc)),
+            (0, RegionCode::Unknown(0), None, None),
+        ];
+
+        for &(nibble, code, region, tv_standard) in &codes {
+            let rom = TestMachine::default().with_bytes(0x7ff0, &header(0, nibble << 4 | 0xc)).into_rom();
+            let info = CartridgeInfo::parse(&rom).unwrap();
+
+            assert_eq!(info.region_code, code);
+            assert_eq!(info.region(), region);
+            assert_eq!(info.tv_standard(), tv_standard);
+        }
+    }
+}
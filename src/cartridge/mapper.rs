@@ -0,0 +1,152 @@
+use savestate::{StateReader, StateWriter};
+
+const BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapperType {
+    // Up to 48KB of ROM mapped straight into the address space
+    None,
+    // The standard Sega mapper, with registers at 0xFFFC-0xFFFF
+    Sega,
+    // Codemasters games, with a register at the start of each slot
+    Codemasters,
+    // Korean releases, which only bank slot 2 through 0xA000
+    Korean,
+}
+
+impl MapperType {
+    // Largest ROM the mapper can address
+    pub fn max_rom_size(&self) -> usize {
+        match *self {
+            MapperType::None => 0xc000,
+            _ => 256 * BANK_SIZE,
+        }
+    }
+
+    // Picks a mapper for a ROM the database doesn't know about
+    pub fn detect(rom: &[u8]) -> MapperType {
+        if rom.len() > 0xc000 {
+            MapperType::Sega
+        } else {
+            MapperType::None
+        }
+    }
+}
+
+// Maps the cartridge ROM, and any on-cartridge RAM, into 0x0000-0xBFFF
+pub struct Mapper {
+    mapper_type: MapperType,
+    // Bank mapped into each 16KB slot
+    banks: [usize; 3],
+    bank_mask: usize,
+    // Sega mapper RAM control register, written at 0xFFFC
+    ram_control: u8,
+    ram: Box<[u8]>,
+}
+
+impl Mapper {
+    pub fn new(mapper_type: MapperType, rom_len: usize) -> Self {
+        let bank_count = rom_len.div_ceil(BANK_SIZE).next_power_of_two();
+
+        Mapper {
+            mapper_type,
+            banks: [0, 1, 2],
+            bank_mask: bank_count - 1,
+            ram_control: 0,
+            ram: vec![0; 0x8000].into_boxed_slice(),
+        }
+    }
+
+    pub fn mapper_type(&self) -> MapperType {
+        self.mapper_type
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for &bank in &self.banks {
+            w.u16(bank as u16);
+        }
+
+        w.u8(self.ram_control);
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        for bank in self.banks.iter_mut() {
+            *bank = r.u16() as usize;
+        }
+
+        self.ram_control = r.u8();
+        r.bytes_into(&mut self.ram);
+    }
+
+    pub fn read(&self, rom: &[u8], addr: u16) -> u8 {
+        let addr = addr as usize;
+
+        if self.mapper_type == MapperType::None {
+            return rom.get(addr).cloned().unwrap_or(0xff);
+        }
+
+        let slot = addr / BANK_SIZE;
+        let offset = addr % BANK_SIZE;
+
+        if slot == 2 && self.ram_enabled() {
+            return self.ram[self.ram_offset() + offset];
+        }
+
+        // The first 1KB is never paged on the Sega mapper, so the interrupt
+        // vectors are always present
+        let bank = if self.mapper_type == MapperType::Sega && addr < 0x400 {
+            0
+        } else {
+            self.banks[slot]
+        };
+
+        rom.get(bank * BANK_SIZE + offset).cloned().unwrap_or(0xff)
+    }
+
+    // ROM bank mapped at addr, None where cartridge RAM is mapped instead
+    pub fn bank(&self, addr: u16) -> Option<usize> {
+        let addr = addr as usize;
+        let slot = addr / BANK_SIZE;
+
+        if self.mapper_type == MapperType::None {
+            return Some(slot);
+        }
+
+        if slot == 2 && self.ram_enabled() {
+            return None;
+        }
+
+        if self.mapper_type == MapperType::Sega && addr < 0x400 {
+            Some(0)
+        } else {
+            Some(self.banks[slot])
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        let bank = val as usize & self.bank_mask;
+
+        match (self.mapper_type, addr) {
+            (MapperType::Sega, 0x8000..=0xbfff) if self.ram_enabled() => {
+                let offset = self.ram_offset() + (addr as usize - 0x8000);
+                self.ram[offset] = val;
+            }
+            (MapperType::Sega, 0xfffc) => self.ram_control = val,
+            (MapperType::Sega, 0xfffd..=0xffff) => self.banks[addr as usize - 0xfffd] = bank,
+            (MapperType::Codemasters, 0x0000) => self.banks[0] = bank,
+            (MapperType::Codemasters, 0x4000) => self.banks[1] = bank,
+            (MapperType::Codemasters, 0x8000) => self.banks[2] = bank,
+            (MapperType::Korean, 0xa000) => self.banks[2] = bank,
+            _ => {}
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_control & 0x08 != 0
+    }
+
+    fn ram_offset(&self) -> usize {
+        if self.ram_control & 0x04 != 0 { BANK_SIZE } else { 0 }
+    }
+}
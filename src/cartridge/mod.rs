@@ -1,33 +1,186 @@
+mod archive;
+mod database;
+mod header;
+mod mapper;
+
 use std::io::{self, Read};
 use std::fs::File;
 use std::path::Path;
 
+use sha1_smol::Sha1;
+
+use region::{Region, TvStandard};
+use savestate::{StateReader, StateWriter};
+use system::System;
+
+pub use self::database::DatabaseEntry;
+pub use self::header::{CartridgeInfo, RegionCode};
+pub use self::mapper::MapperType;
+
+use self::mapper::Mapper;
+
 pub struct Cartridge {
     rom: Box<[u8]>,
+    mapper: Mapper,
+    info: Option<CartridgeInfo>,
+    game: Option<DatabaseEntry>,
+    // System guessed from the file name the ROM was loaded from
+    system: Option<System>,
 }
 
+// Copiers prepend a 512 byte header to ROM dumps
+const COPIER_HEADER_SIZE: usize = 512;
+
 impl Cartridge {
     pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Cartridge> {
+        Cartridge::load_entry(file_name, None)
+    }
+
+    // Loads a ROM from a file, which may be a zip or gzip archive. From a zip
+    // the named entry is loaded, or without a name the first ROM found.
+    pub fn load_entry<P: AsRef<Path>>(file_name: P, entry: Option<&str>) -> io::Result<Cartridge> {
+        let file_name = file_name.as_ref();
+
         let mut file = File::open(file_name)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        let cart = Cartridge::from_bytes(&buffer);
+        let (rom, rom_name) = archive::extract(buffer, file_name, entry)?;
 
-        println!("Loaded {:0x} bytes of cart", buffer.len());
+        let mut cart = Cartridge::from_bytes(&rom);
+        cart.system = System::from_file_name(&rom_name);
 
         Ok(cart)
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Cartridge {
+        let bytes = if bytes.len() % 0x400 == COPIER_HEADER_SIZE {
+            &bytes[COPIER_HEADER_SIZE..]
+        } else {
+            bytes
+        };
+
         let bytes_copy = bytes.to_vec();
+        let game = database::lookup(bytes);
+
+        let mapper_type = game.as_ref()
+            .and_then(|game| game.mapper)
+            .unwrap_or_else(|| MapperType::detect(bytes));
 
         Cartridge {
+            mapper: Mapper::new(mapper_type, bytes.len()),
+            info: CartridgeInfo::parse(bytes),
+            game,
+            system: None,
             rom: bytes_copy.into_boxed_slice(),
         }
     }
 
+    // The parsed Sega header, None if the ROM doesn't have one
+    pub fn info(&self) -> Option<&CartridgeInfo> {
+        self.info.as_ref()
+    }
+
+    // The game database's settings for this ROM, if it's a known game
+    pub fn game(&self) -> Option<&DatabaseEntry> {
+        self.game.as_ref()
+    }
+
+    // Region from the game database, falling back to the cartridge header
+    pub fn region(&self) -> Option<Region> {
+        self.game.as_ref()
+            .and_then(|game| game.region)
+            .or_else(|| self.info.as_ref().and_then(|info| info.region()))
+    }
+
+    // TV standard from the game database, falling back to the cartridge header
+    pub fn tv_standard(&self) -> Option<TvStandard> {
+        self.game.as_ref()
+            .and_then(|game| game.tv_standard)
+            .or_else(|| self.info.as_ref().and_then(|info| info.tv_standard()))
+    }
+
+    // The system the ROM is for, from its file extension or failing that a
+    // Game Gear region code in its header. Game Gear games the database lists
+    // as running in SMS mode are Master System games.
+    pub fn system(&self) -> Option<System> {
+        if self.game.as_ref().and_then(|game| game.gg_sms_mode) == Some(true) {
+            return Some(System::Sms);
+        }
+
+        self.system.or_else(|| {
+            match self.info.as_ref()?.region_code {
+                RegionCode::GameGearJapan | RegionCode::GameGearExport |
+                    RegionCode::GameGearInternational => Some(System::GameGear),
+                RegionCode::SmsJapan | RegionCode::SmsExport => Some(System::Sms),
+                RegionCode::Unknown(_) => None,
+            }
+        })
+    }
+
+    // Identifies the ROM a save state was made with
+    pub fn crc32(&self) -> u32 {
+        crc32fast::hash(&self.rom)
+    }
+
+    // Identifies the ROM a movie was recorded with
+    pub fn sha1(&self) -> [u8; 20] {
+        Sha1::from(&self.rom).digest().bytes()
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.mapper.save_state(w);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) {
+        self.mapper.load_state(r);
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom.len()
+    }
+
+    pub fn mapper_type(&self) -> MapperType {
+        self.mapper.mapper_type()
+    }
+
+    pub fn set_mapper_type(&mut self, mapper_type: MapperType) {
+        self.mapper = Mapper::new(mapper_type, self.rom.len());
+    }
+
     pub fn read_u8(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        self.mapper.read(&self.rom, addr)
+    }
+
+    // ROM bank mapped at addr, which must be below 0xC000
+    pub fn bank(&self, addr: u16) -> Option<usize> {
+        self.mapper.bank(addr)
+    }
+
+    // Writes to the mapper's registers or on-cartridge RAM
+    pub fn write_u8(&mut self, addr: u16, val: u8) {
+        self.mapper.write(addr, val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TestMachine;
+
+    #[test]
+    fn copier_header_is_stripped() {
+        let rom = TestMachine::default().into_rom();
+        let mut dump = vec![0xff; COPIER_HEADER_SIZE];
+        dump.extend_from_slice(&rom);
+
+        let cart = Cartridge::from_bytes(&dump);
+        assert_eq!(cart.rom_size(), rom.len());
+        assert_eq!(cart.crc32(), crc32fast::hash(&rom));
+        assert_eq!(cart.read_u8(0), rom[0]);
+
+        // Only an odd 512 bytes on top of whole kilobytes is taken as a header
+        let rom = TestMachine::default().with_size(0x8000 + 0x400).into_rom();
+        assert_eq!(Cartridge::from_bytes(&rom).rom_size(), rom.len());
     }
 }
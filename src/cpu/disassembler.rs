@@ -0,0 +1,406 @@
+use std::fmt;
+
+use super::io::{Operand, Src8, Src16, Dst8, Dst16};
+use super::mnemonics;
+use super::operands::{Register16, Address, PortAddress, Condition};
+use super::operations::{self, Operations};
+
+// Somewhere to read instructions from. Unlike Z80Bus reads can't have side
+// effects, so disassembling never disturbs the machine.
+pub trait Memory {
+    fn peek8(&self, addr: u16) -> u8;
+
+    // The bank of ROM paged in at addr, for memory behind a mapper. Used to
+    // tell apart labels that share an address in different banks.
+    fn bank(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+}
+
+// A flat image starting at address 0, reading 0xff past the end
+impl Memory for [u8] {
+    fn peek8(&self, addr: u16) -> u8 {
+        self.get(addr as usize).cloned().unwrap_or(0xff)
+    }
+}
+
+// A single decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // Mnemonic and operands, e.g. "ld a,(hl)"
+    pub text: String,
+    // Where a jump, call or relative jump goes if it is taken
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Address of the instruction that follows this one
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Decodes the instruction at addr. Opcodes the executor runs are decoded the
+// same way they're executed, through the Operations visitor, and everything
+// else from the full table in mnemonics.
+pub fn disassemble<M: Memory + ?Sized>(memory: &M, addr: u16) -> Instruction {
+    let mut disassembler = Disassembler {
+        cursor: Cursor { memory, pc: addr },
+        text: String::new(),
+        target: None,
+    };
+
+    let (text, target) = match operations::visit(&mut disassembler) {
+        Ok(()) => (disassembler.text, disassembler.target),
+        Err(_) => {
+            disassembler.cursor.seek(addr);
+            mnemonics::decode(&mut disassembler.cursor)
+        }
+    };
+
+    let len = disassembler.cursor.pc.wrapping_sub(addr);
+    let bytes = (0..len).map(|i| memory.peek8(addr.wrapping_add(i))).collect();
+
+    Instruction {
+        addr,
+        bytes,
+        text,
+        target,
+    }
+}
+
+// Reads through the bytes of an instruction as the operands are decoded
+pub struct Cursor<'a, M: Memory + ?Sized + 'a> {
+    memory: &'a M,
+    pc: u16,
+}
+
+impl<'a, M: Memory + ?Sized> Cursor<'a, M> {
+    pub fn next8(&mut self) -> u8 {
+        let val = self.memory.peek8(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+
+        val
+    }
+
+    pub fn next16(&mut self) -> u16 {
+        let lb = self.next8() as u16;
+        let hb = self.next8() as u16;
+
+        (hb << 8) | lb
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn seek(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+}
+
+struct Disassembler<'a, M: Memory + ?Sized + 'a> {
+    cursor: Cursor<'a, M>,
+    text: String,
+    target: Option<u16>,
+}
+
+impl<'a, M: Memory + ?Sized> Disassembler<'a, M> {
+    fn emit(&mut self, mnemonic: &str, operands: &[String]) {
+        self.text = if operands.is_empty() {
+            mnemonic.to_string()
+        } else {
+            format!("{} {}", mnemonic, operands.join(","))
+        };
+    }
+
+    // Conditional forms put the condition before the other operands
+    fn emit_conditional<C: Condition>(&mut self, mnemonic: &str, cond: C, operand: Option<String>) {
+        let operands = Some(cond.mnemonic())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .into_iter()
+            .chain(operand)
+            .collect::<Vec<_>>();
+
+        self.emit(mnemonic, &operands);
+    }
+
+    fn branch(&mut self, addr: Address) -> String {
+        match addr {
+            Address::Direct => {
+                let target = self.cursor.next16();
+                self.target = Some(target);
+
+                format!("${:04x}", target)
+            }
+            _ => addr.disassemble(&mut self.cursor),
+        }
+    }
+}
+
+impl<'a, 'b, M: Memory + ?Sized> Operations for &'b mut Disassembler<'a, M> {
+    fn read_opcode(&mut self) -> u8 {
+        self.cursor.next8()
+    }
+
+    fn read_extended_opcode(&mut self) -> u8 {
+        self.cursor.next8()
+    }
+
+    fn load8<S: Src8, D: Dst8>(&mut self, dst: D, src: S) {
+        let dst = dst.disassemble(&mut self.cursor);
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("ld", &[dst, src]);
+    }
+
+    fn load16<S: Src16, D: Dst16>(&mut self, dst: D, src: S) {
+        let dst = dst.disassemble(&mut self.cursor);
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("ld", &[dst, src]);
+    }
+
+    fn push16<S: Src16>(&mut self, src: S) {
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("push", &[src]);
+    }
+
+    fn pop16<D: Dst16>(&mut self, dst: D) {
+        let dst = dst.disassemble(&mut self.cursor);
+        self.emit("pop", &[dst]);
+    }
+
+    fn ex_de_hl(&mut self) {
+        self.emit("ex", &["de".to_string(), "hl".to_string()]);
+    }
+
+    fn ldi(&mut self) {
+        self.emit("ldi", &[]);
+    }
+
+    fn ldir(&mut self) {
+        self.emit("ldir", &[]);
+    }
+
+    fn xor<S: Src8>(&mut self, src: S) {
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("xor", &[src]);
+    }
+
+    fn or<S: Src8>(&mut self, src: S) {
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("or", &[src]);
+    }
+
+    fn cp<S: Src8>(&mut self, src: S) {
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("cp", &[src]);
+    }
+
+    fn disable_interrupts(&mut self) {
+        self.emit("di", &[]);
+    }
+
+    fn enable_interrupts(&mut self) {
+        self.emit("ei", &[]);
+    }
+
+    fn halt(&mut self) {
+        self.emit("halt", &[]);
+    }
+
+    fn set_interrupt_mode(&mut self, interrupt_mode: u8) {
+        self.emit("im", &[interrupt_mode.to_string()]);
+    }
+
+    fn add16(&mut self, d: Register16, s: Register16) {
+        let d = d.disassemble(&mut self.cursor);
+        let s = s.disassemble(&mut self.cursor);
+        self.emit("add", &[d, s]);
+    }
+
+    fn inc16(&mut self, r: Register16) {
+        let r = r.disassemble(&mut self.cursor);
+        self.emit("inc", &[r]);
+    }
+
+    fn dec16(&mut self, r: Register16) {
+        let r = r.disassemble(&mut self.cursor);
+        self.emit("dec", &[r]);
+    }
+
+    fn jump<C: Condition>(&mut self, addr: Address, cond: C) {
+        let addr = self.branch(addr);
+        self.emit_conditional("jp", cond, Some(addr));
+    }
+
+    fn jr<C: Condition>(&mut self, cond: C) {
+        let offset = self.cursor.next8();
+        let target = self.cursor.pc.wrapping_add(offset as i8 as u16);
+        self.target = Some(target);
+
+        self.emit_conditional("jr", cond, Some(format!("${:04x}", target)));
+    }
+
+    fn call<C: Condition>(&mut self, addr: Address, cond: C) {
+        let addr = self.branch(addr);
+        self.emit_conditional("call", cond, Some(addr));
+    }
+
+    fn ret(&mut self) {
+        self.emit("ret", &[]);
+    }
+
+    fn reti(&mut self) {
+        self.emit("reti", &[]);
+    }
+
+    fn retn(&mut self) {
+        self.emit("retn", &[]);
+    }
+
+    fn out<S: Src8>(&mut self, addr: PortAddress, src: S) {
+        let addr = addr.disassemble(&mut self.cursor);
+        let src = src.disassemble(&mut self.cursor);
+        self.emit("out", &[addr, src]);
+    }
+
+    fn input<D: Dst8>(&mut self, dst: D, addr: PortAddress) {
+        let dst = dst.disassemble(&mut self.cursor);
+        let addr = addr.disassemble(&mut self.cursor);
+        self.emit("in", &[dst, addr]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> (String, usize) {
+        let instruction = disassemble(bytes, 0);
+
+        (instruction.text.clone(), instruction.len())
+    }
+
+    fn check(bytes: &[u8], expected: &str, len: usize) {
+        assert_eq!(text(bytes), (expected.to_string(), len), "{:02x?}", bytes);
+    }
+
+    // The table has to agree with the visitor wherever the executor runs an
+    // opcode, which also catches the visitor decoding one wrongly
+    #[test]
+    fn table_matches_the_visitor() {
+        let prefixes = [None, Some(0xed)];
+
+        for &prefix in &prefixes {
+            for opcode in 0..=0xffu8 {
+                let bytes = prefix.into_iter().chain(vec![opcode, 0x34, 0x12, 0x56]).collect::<Vec<_>>();
+
+                let mut disassembler = Disassembler {
+                    cursor: Cursor { memory: &bytes[..], pc: 0 },
+                    text: String::new(),
+                    target: None,
+                };
+                if operations::visit(&mut disassembler).is_err() {
+                    continue;
+                }
+
+                let mut cursor = Cursor { memory: &bytes[..], pc: 0 };
+                let (text, target) = mnemonics::decode(&mut cursor);
+
+                assert_eq!(text, disassembler.text, "{:02x?}", bytes);
+                assert_eq!(target, disassembler.target, "{:02x?}", bytes);
+                assert_eq!(cursor.pc, disassembler.cursor.pc, "{:02x?}", bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn loads() {
+        check(&[0x5e], "ld e,(hl)", 1);
+        check(&[0x6e], "ld l,(hl)", 1);
+        check(&[0x2a, 0x34, 0x12], "ld hl,($1234)", 3);
+        check(&[0xed, 0x43, 0x34, 0x12], "ld ($1234),bc", 4);
+        check(&[0xed, 0x57], "ld a,i", 2);
+    }
+
+    #[test]
+    fn arithmetic() {
+        check(&[0x3c], "inc a", 1);
+        check(&[0x35], "dec (hl)", 1);
+        check(&[0x80], "add a,b", 1);
+        check(&[0x9e], "sbc a,(hl)", 1);
+        check(&[0xd6, 0x10], "sub $10", 2);
+        check(&[0xe6, 0x0f], "and $0f", 2);
+        check(&[0xed, 0x52], "sbc hl,de", 2);
+        check(&[0x27], "daa", 1);
+    }
+
+    #[test]
+    fn branches() {
+        let instruction = disassemble(&[0x00, 0x00, 0x10, 0xfc][..], 2);
+        assert_eq!(instruction.text, "djnz $0000");
+        assert_eq!(instruction.target, Some(0));
+
+        let instruction = disassemble(&[0xff][..], 0);
+        assert_eq!(instruction.text, "rst $38");
+        assert_eq!(instruction.target, Some(0x38));
+
+        check(&[0xd8], "ret c", 1);
+        check(&[0xea, 0x34, 0x12], "jp pe,$1234", 3);
+        check(&[0xfc, 0x34, 0x12], "call m,$1234", 3);
+        check(&[0xe9], "jp (hl)", 1);
+        check(&[0xed, 0xb8], "lddr", 2);
+    }
+
+    #[test]
+    fn bit_operations() {
+        check(&[0xcb, 0x00], "rlc b", 2);
+        check(&[0xcb, 0x36], "sll (hl)", 2);
+        check(&[0xcb, 0x7e], "bit 7,(hl)", 2);
+        check(&[0xcb, 0x87], "res 0,a", 2);
+        check(&[0xcb, 0xd9], "set 3,c", 2);
+    }
+
+    #[test]
+    fn index_registers() {
+        check(&[0xdd, 0x21, 0x34, 0x12], "ld ix,$1234", 4);
+        check(&[0xfd, 0x7e, 0xfe], "ld a,(iy-$02)", 3);
+        check(&[0xdd, 0x66, 0x05], "ld h,(ix+$05)", 3);
+        check(&[0xdd, 0x36, 0x05, 0x80], "ld (ix+$05),$80", 4);
+        check(&[0xfd, 0x26, 0x80], "ld iyh,$80", 3);
+        check(&[0xdd, 0x85], "add a,ixl", 2);
+        check(&[0xfd, 0x09], "add iy,bc", 2);
+        check(&[0xdd, 0xe3], "ex (sp),ix", 2);
+        check(&[0xfd, 0xe9], "jp (iy)", 2);
+        check(&[0xdd, 0xcb, 0x05, 0x46], "bit 0,(ix+$05)", 4);
+        check(&[0xfd, 0xcb, 0xff, 0xce], "set 1,(iy-$01)", 4);
+        check(&[0xdd, 0xcb, 0x05, 0x00], "rlc (ix+$05),b", 4);
+    }
+
+    // Opcodes that don't do anything are still given their real length
+    #[test]
+    fn no_ops() {
+        check(&[0xdd, 0x00], "db $dd", 1);
+        check(&[0xfd, 0xeb], "db $fd", 1);
+        check(&[0xdd, 0xfd, 0x21, 0x34, 0x12], "db $dd", 1);
+        check(&[0xed, 0x00], "db $ed,$00", 2);
+        check(&[0xed, 0x77], "db $ed,$77", 2);
+    }
+}
@@ -0,0 +1,42 @@
+use std::error;
+use std::fmt;
+
+// An opcode along with the prefix byte it followed, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub prefix: Option<u8>,
+    pub opcode: u8,
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "0x{:02x} 0x{:02x}", prefix, self.opcode),
+            None => write!(f, "0x{:02x}", self.opcode),
+        }
+    }
+}
+
+// Why the Z80 couldn't execute an instruction. The CPU is left as it was just
+// before the instruction, pointing at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    // An opcode the core doesn't implement yet
+    UnimplementedOpcode { pc: u16, opcode: Opcode },
+    // An opcode that isn't defined on the Z80, only reported in strict mode
+    IllegalOpcode { pc: u16, opcode: Opcode },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnimplementedOpcode { pc, opcode } => {
+                write!(f, "Unimplemented opcode {} at 0x{:04x}", opcode, pc)
+            }
+            Error::IllegalOpcode { pc, opcode } => write!(f, "Illegal opcode {} at 0x{:04x}", opcode, pc),
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -2,28 +2,28 @@ use super::io::{Src8, Src16, Dst8, Dst16};
 use super::operands::{Register16, Address, PortAddress, Condition};
 use super::operations::Operations;
 use super::state::{State, Flags};
-use super::super::bus::Bus;
+use super::timing;
+use super::z80_bus::Z80Bus;
 
-pub struct Executor<'a> (pub &'a mut State, pub &'a mut Bus);
-
-// TODO - timings
-impl<'a> Operations for Executor<'a> {
-    fn dump_state(&self) {
-        println!("PC: {:04x}", self.0.pc);
-    }
+pub struct Executor<'a, B: Z80Bus + 'a> (pub &'a mut State, pub &'a mut B);
 
+impl<'a, B: Z80Bus> Operations for Executor<'a, B> {
     fn read_opcode(&mut self) -> u8 {
         let pc = self.0.pc;
-        self.0.pc += 1;
-        let op = self.1.read8(pc);
+        self.0.pc = pc.wrapping_add(1);
+        let op = self.0.fetch8(self.1, pc);
+        self.0.cycles += timing::CYCLES[op as usize] as u64;
 
         op
     }
 
     fn read_extended_opcode(&mut self) -> u8 {
         let pc = self.0.pc;
-        self.0.pc += 1;
-        self.1.read8(pc)
+        self.0.pc = pc.wrapping_add(1);
+        let op = self.0.fetch8(self.1, pc);
+        self.0.cycles += timing::ED_CYCLES[op as usize] as u64;
+
+        op
     }
 
     fn load8<S: Src8, D: Dst8>(&mut self, dst: D, src: S) {
@@ -57,11 +57,11 @@ impl<'a> Operations for Executor<'a> {
     fn ldi(&mut self) {
         // (DE) ← (HL), DE ← DE + 1, HL ← HL + 1, BC ← BC – 1
         let src_addr = self.0.hl();
-        let val = self.1.read8(src_addr);
+        let val = self.0.read8(self.1, src_addr);
         let dst_addr = self.0.de();
         let bc = self.0.bc();
 
-        self.1.write8(dst_addr, val);
+        self.0.write8(self.1, dst_addr, val);
 
         self.0.set_de(dst_addr.wrapping_add(1));
         self.0.set_hl(src_addr.wrapping_add(1));
@@ -73,6 +73,7 @@ impl<'a> Operations for Executor<'a> {
 
         if self.0.bc() != 0 {
             self.0.pc = self.0.pc.wrapping_sub(2);
+            self.0.cycles += timing::BLOCK_REPEAT as u64;
         }
     }
 
@@ -81,13 +82,13 @@ impl<'a> Operations for Executor<'a> {
 
         let r_sign = self.0.r >> 7;
 
-        self.0.a = self.0.a ^ val;
+        self.0.a ^= val;
 
         self.0.f.set(Flags::S, r_sign == 1);
         self.0.f.set(Flags::H, false);
         self.0.f.set(Flags::Z, self.0.a == 0);
         self.0.f.set(Flags::N, false);
-        self.0.f.set(Flags::P, self.0.a.count_ones() % 2 == 0);
+        self.0.f.set(Flags::P, self.0.a.count_ones().is_multiple_of(2));
         self.0.f.set(Flags::C, false);
     }
 
@@ -98,7 +99,7 @@ impl<'a> Operations for Executor<'a> {
         let v_sign = val >> 7;
         let r_sign = self.0.r >> 7;
 
-        self.0.a = self.0.a | val;
+        self.0.a |= val;
 
         self.0.f.set(Flags::S, r_sign == 1);
         self.0.f.set(Flags::H, false);
@@ -119,6 +120,16 @@ impl<'a> Operations for Executor<'a> {
         self.0.iff2 = false;
     }
 
+    fn enable_interrupts(&mut self) {
+        self.0.iff1 = true;
+        self.0.iff2 = true;
+        self.0.ei_delay = true;
+    }
+
+    fn halt(&mut self) {
+        self.0.halted = true;
+    }
+
     fn set_interrupt_mode(&mut self, interrupt_mode: u8) {
         self.0.interrupt_mode = interrupt_mode;
     }
@@ -158,6 +169,7 @@ impl<'a> Operations for Executor<'a> {
 
         if c.check(self.0) {
             self.0.pc = self.0.pc.wrapping_add(offset as i8 as u16);
+            self.0.cycles += timing::JR_TAKEN as u64;
         }
     }
 
@@ -170,6 +182,7 @@ impl<'a> Operations for Executor<'a> {
             self.0.push16(self.1, pc);
 
             self.0.pc = addr;
+            self.0.cycles += timing::CALL_TAKEN as u64;
         }
     }
 
@@ -178,15 +191,48 @@ impl<'a> Operations for Executor<'a> {
         self.0.pc = pc;
     }
 
+    fn reti(&mut self) {
+        self.ret();
+        self.0.iff1 = self.0.iff2;
+    }
+
+    fn retn(&mut self) {
+        self.ret();
+        self.0.iff1 = self.0.iff2;
+    }
+
     fn out<S: Src8>(&mut self, addr: PortAddress, src: S) {
         let val = src.src8(self.0, self.1);
         let addr = addr.indirect(self.0, self.1);
 
+        // The access lands at the end of the instruction, close enough to
+        // where the Z80 puts it
+        self.0.cycles += self.1.io_contention(addr, self.0.cycles) as u64;
+        self.1.advance_to(self.0.cycles);
         self.1.out8(addr, val);
     }
+
+    fn input<D: Dst8>(&mut self, dst: D, addr: PortAddress) {
+        let port = addr.indirect(self.0, self.1);
+
+        self.0.cycles += self.1.io_contention(port, self.0.cycles) as u64;
+        self.1.advance_to(self.0.cycles);
+        let val = self.1.in8(port);
+
+        dst.dst8(self.0, self.1, val);
+
+        // IN A, (n) leaves the flags alone, IN r, (C) sets them from the value read
+        if let PortAddress::Indirect = addr {
+            self.0.f.set(Flags::S, val & 0x80 != 0);
+            self.0.f.set(Flags::H, false);
+            self.0.f.set(Flags::Z, val == 0);
+            self.0.f.set(Flags::N, false);
+            self.0.f.set(Flags::P, val.count_ones().is_multiple_of(2));
+        }
+    }
 }
 
-impl<'a> Executor<'a> {
+impl<'a, B: Z80Bus> Executor<'a, B> {
     fn subc_impl(&mut self, val: u8, carry: bool) -> u8 {
         let carry = if carry { 1 } else { 0 };
         let (tmp, underflow) = self.0.a.overflowing_sub(val);
@@ -1,18 +1,25 @@
-use super::super::bus::Bus;
 use super::State;
+use super::disassembler::{Cursor, Memory};
+use super::z80_bus::Z80Bus;
 
-pub trait Src8: Copy {
-    fn src8(&self, state: &mut State, bus: &mut Bus) -> u8;
+// How an operand is written in disassembly, immediate values are read from
+// the instruction stream as they are in execution
+pub trait Operand {
+    fn disassemble<M: Memory + ?Sized>(&self, cursor: &mut Cursor<M>) -> String;
 }
 
-pub trait Dst8: Copy {
-    fn dst8(&self, state: &mut State, bus: &mut Bus, value: u8);
+pub trait Src8: Copy + Operand {
+    fn src8<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u8;
 }
 
-pub trait Src16: Copy {
-    fn src16(&self, state: &mut State, bus: &mut Bus) -> u16;
+pub trait Dst8: Copy + Operand {
+    fn dst8<B: Z80Bus>(&self, state: &mut State, bus: &mut B, value: u8);
 }
 
-pub trait Dst16: Copy {
-    fn dst16(&self, state: &mut State, bus: &mut Bus, value: u16);
+pub trait Src16: Copy + Operand {
+    fn src16<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u16;
+}
+
+pub trait Dst16: Copy + Operand {
+    fn dst16<B: Z80Bus>(&self, state: &mut State, bus: &mut B, value: u16);
 }
@@ -0,0 +1,284 @@
+use super::disassembler::{Cursor, Memory};
+
+// A table of every Z80 instruction, decoded from the fields of the opcode
+// byte as laid out in "Decoding Z80 Opcodes":
+//
+//   x = bits 7-6, y = bits 5-3, z = bits 2-0, p = bits 5-4, q = bit 3
+//
+// It only knows lengths and mnemonics, so it covers opcodes the executor
+// doesn't run yet, including the undocumented ones.
+
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const PAIRS: [&str; 4] = ["bc", "de", "hl", "sp"];
+const PAIRS_AF: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 8] = ["nz", "z", "nc", "c", "po", "pe", "p", "m"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub ", "sbc a,", "and ", "xor ", "or ", "cp "];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "sll", "srl"];
+const INTERRUPT_MODES: [&str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+const ACCUMULATOR: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["ldi", "cpi", "ini", "outi"],
+    ["ldd", "cpd", "ind", "outd"],
+    ["ldir", "cpir", "inir", "otir"],
+    ["lddr", "cpdr", "indr", "otdr"],
+];
+
+// Decodes the instruction at the cursor, returning its text and where it
+// branches to
+pub fn decode<M: Memory + ?Sized>(cursor: &mut Cursor<M>) -> (String, Option<u16>) {
+    let start = cursor.pc();
+    let opcode = cursor.next8();
+
+    let mut decoder = Decoder { cursor, index: None, displacement: None, indexed: false, target: None };
+
+    let text = match opcode {
+        0xcb => decoder.cb(),
+        0xed => decoder.ed(),
+        0xdd | 0xfd => {
+            decoder.index = Some(if opcode == 0xdd { "ix" } else { "iy" });
+
+            match decoder.cursor.next8() {
+                0xcb => decoder.index_cb(),
+                // Prefixing an instruction that doesn't use HL has no
+                // effect, the prefix is a NOP on its own
+                0xdd | 0xed | 0xfd => String::new(),
+                opcode => decoder.unprefixed(opcode),
+            }
+        }
+        opcode => decoder.unprefixed(opcode),
+    };
+
+    let text = match (decoder.index.is_some(), decoder.indexed) {
+        (true, false) => {
+            decoder.cursor.seek(start.wrapping_add(1));
+            format!("db ${:02x}", opcode)
+        }
+        _ => text,
+    };
+
+    (text, decoder.target)
+}
+
+struct Decoder<'a, 'b, M: Memory + ?Sized + 'a + 'b> {
+    cursor: &'b mut Cursor<'a, M>,
+    // The index register a DD or FD prefix substitutes for HL
+    index: Option<&'static str>,
+    // An (ix+d) displacement already read, which DDCB puts before the opcode
+    displacement: Option<i8>,
+    // Whether the prefix did anything
+    indexed: bool,
+    target: Option<u16>,
+}
+
+impl<'a, 'b, M: Memory + ?Sized> Decoder<'a, 'b, M> {
+    fn imm8(&mut self) -> String {
+        format!("${:02x}", self.cursor.next8())
+    }
+
+    fn imm16(&mut self) -> String {
+        format!("${:04x}", self.cursor.next16())
+    }
+
+    fn branch(&mut self) -> String {
+        let target = self.cursor.next16();
+        self.target = Some(target);
+
+        format!("${:04x}", target)
+    }
+
+    fn relative(&mut self) -> String {
+        let offset = self.cursor.next8();
+        let target = self.cursor.pc().wrapping_add(offset as i8 as u16);
+        self.target = Some(target);
+
+        format!("${:04x}", target)
+    }
+
+    fn hl(&mut self) -> &'static str {
+        match self.index {
+            Some(index) => {
+                self.indexed = true;
+                index
+            }
+            None => "hl",
+        }
+    }
+
+    fn indirect_hl(&mut self) -> String {
+        let index = match self.index {
+            Some(index) => index,
+            None => return "(hl)".to_string(),
+        };
+
+        self.indexed = true;
+        let d = match self.displacement {
+            Some(d) => d,
+            None => self.cursor.next8() as i8,
+        };
+
+        if d < 0 {
+            format!("({}-${:02x})", index, -(d as i16))
+        } else {
+            format!("({}+${:02x})", index, d)
+        }
+    }
+
+    // Register r[i], with H and L replaced by the halves of the index
+    // register unless (ix+d) is also an operand
+    fn register(&mut self, i: u8, other: Option<u8>) -> String {
+        match (i, self.index) {
+            (6, _) => self.indirect_hl(),
+            (4, Some(index)) | (5, Some(index)) if other != Some(6) => {
+                self.indexed = true;
+                format!("{}{}", index, REGISTERS[i as usize])
+            }
+            _ => REGISTERS[i as usize].to_string(),
+        }
+    }
+
+    fn pair(&mut self, p: u8) -> String {
+        match p {
+            2 => self.hl().to_string(),
+            _ => PAIRS[p as usize].to_string(),
+        }
+    }
+
+    fn pair_af(&mut self, p: u8) -> String {
+        match p {
+            2 => self.hl().to_string(),
+            _ => PAIRS_AF[p as usize].to_string(),
+        }
+    }
+
+    fn unprefixed(&mut self, opcode: u8) -> String {
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => "nop".to_string(),
+                1 => "ex af,af'".to_string(),
+                2 => format!("djnz {}", self.relative()),
+                3 => format!("jr {}", self.relative()),
+                _ => format!("jr {},{}", CONDITIONS[(y - 4) as usize], self.relative()),
+            },
+            (0, 1) if q == 0 => {
+                let pair = self.pair(p);
+                format!("ld {},{}", pair, self.imm16())
+            }
+            (0, 1) => format!("add {},{}", self.hl(), self.pair(p)),
+            (0, 2) => match (p, q) {
+                (0, 0) => "ld (bc),a".to_string(),
+                (1, 0) => "ld (de),a".to_string(),
+                (2, 0) => format!("ld ({}),{}", self.imm16(), self.hl()),
+                (3, 0) => format!("ld ({}),a", self.imm16()),
+                (0, _) => "ld a,(bc)".to_string(),
+                (1, _) => "ld a,(de)".to_string(),
+                (2, _) => format!("ld {},({})", self.hl(), self.imm16()),
+                _ => format!("ld a,({})", self.imm16()),
+            },
+            (0, 3) => format!("{} {}", ["inc", "dec"][q as usize], self.pair(p)),
+            (0, 4) => format!("inc {}", self.register(y, None)),
+            (0, 5) => format!("dec {}", self.register(y, None)),
+            (0, 6) => {
+                let dst = self.register(y, None);
+                format!("ld {},{}", dst, self.imm8())
+            }
+            (0, _) => ACCUMULATOR[y as usize].to_string(),
+            (1, 6) if y == 6 => "halt".to_string(),
+            (1, _) => {
+                let dst = self.register(y, Some(z));
+                format!("ld {},{}", dst, self.register(z, Some(y)))
+            }
+            (2, _) => format!("{}{}", ALU[y as usize], self.register(z, None)),
+            (_, 0) => format!("ret {}", CONDITIONS[y as usize]),
+            (_, 1) => match (q, p) {
+                (0, _) => format!("pop {}", self.pair_af(p)),
+                (_, 0) => "ret".to_string(),
+                (_, 1) => "exx".to_string(),
+                (_, 2) => format!("jp ({})", self.hl()),
+                _ => format!("ld sp,{}", self.hl()),
+            },
+            (_, 2) => format!("jp {},{}", CONDITIONS[y as usize], self.branch()),
+            (_, 3) => match y {
+                0 => format!("jp {}", self.branch()),
+                2 => format!("out ({}),a", self.imm8()),
+                3 => format!("in a,({})", self.imm8()),
+                4 => format!("ex (sp),{}", self.hl()),
+                5 => "ex de,hl".to_string(),
+                6 => "di".to_string(),
+                // 0xcb is taken care of before getting here
+                _ => "ei".to_string(),
+            },
+            (_, 4) => format!("call {},{}", CONDITIONS[y as usize], self.branch()),
+            (_, 5) if q == 0 => format!("push {}", self.pair_af(p)),
+            // The prefixes are taken care of before getting here
+            (_, 5) => format!("call {}", self.branch()),
+            (_, 6) => format!("{}{}", ALU[y as usize], self.imm8()),
+            _ => {
+                self.target = Some(y as u16 * 8);
+                format!("rst ${:02x}", y * 8)
+            }
+        }
+    }
+
+    fn cb(&mut self) -> String {
+        let opcode = self.cursor.next8();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let r = REGISTERS[z as usize];
+
+        match x {
+            0 => format!("{} {}", ROTATES[y as usize], r),
+            1 => format!("bit {},{}", y, r),
+            2 => format!("res {},{}", y, r),
+            _ => format!("set {},{}", y, r),
+        }
+    }
+
+    // DDCB and FDCB, where the displacement comes before the opcode. Besides
+    // the documented (ix+d) forms, the result is also copied to a register.
+    fn index_cb(&mut self) -> String {
+        self.displacement = Some(self.cursor.next8() as i8);
+
+        let opcode = self.cursor.next8();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let operand = self.indirect_hl();
+
+        let text = match x {
+            0 => format!("{} {}", ROTATES[y as usize], operand),
+            1 => return format!("bit {},{}", y, operand),
+            2 => format!("res {},{}", y, operand),
+            _ => format!("set {},{}", y, operand),
+        };
+
+        match z {
+            6 => text,
+            _ => format!("{},{}", text, REGISTERS[z as usize]),
+        }
+    }
+
+    fn ed(&mut self) -> String {
+        let opcode = self.cursor.next8();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        let r = REGISTERS[y as usize];
+
+        match (x, z) {
+            (1, 0) if y == 6 => "in (c)".to_string(),
+            (1, 0) => format!("in {},(c)", r),
+            (1, 1) if y == 6 => "out (c),0".to_string(),
+            (1, 1) => format!("out (c),{}", r),
+            (1, 2) => format!("{} hl,{}", ["sbc", "adc"][q as usize], PAIRS[p as usize]),
+            (1, 3) if q == 0 => format!("ld ({}),{}", self.imm16(), PAIRS[p as usize]),
+            (1, 3) => format!("ld {},({})", PAIRS[p as usize], self.imm16()),
+            (1, 4) => "neg".to_string(),
+            (1, 5) if y == 1 => "reti".to_string(),
+            (1, 5) => "retn".to_string(),
+            (1, 6) => format!("im {}", INTERRUPT_MODES[y as usize]),
+            (1, 7) if y < 6 => ["ld i,a", "ld r,a", "ld a,i", "ld a,r", "rrd", "rld"][y as usize].to_string(),
+            (2, 0..=3) if y >= 4 => BLOCK[(y - 4) as usize][z as usize].to_string(),
+            // Anything else is a two byte NOP
+            _ => format!("db $ed,${:02x}", opcode),
+        }
+    }
+}
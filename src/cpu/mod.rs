@@ -1,27 +1,190 @@
+mod disassembler;
+mod error;
 mod executor;
 mod io;
+mod mnemonics;
 mod operands;
 mod operations;
 mod state;
+mod timing;
+mod z80_bus;
+
+pub use self::disassembler::{disassemble, Instruction, Memory};
+pub use self::error::{Error, Opcode};
+pub use self::state::{Flags, State};
+pub use self::z80_bus::Z80Bus;
 
-use self::state::State;
 use self::executor::Executor;
-use super::bus::Bus;
+use self::operations::UnknownOpcode;
+use super::savestate::{StateReader, StateWriter};
 
 pub struct Cpu {
     state: State,
+    // Report opcodes the Z80 doesn't define rather than running them as NOPs
+    strict: bool,
 }
 
 impl Cpu {
-    pub fn new() -> Self {
+    pub fn new(strict: bool) -> Self {
         Cpu {
             state: State::default(),
+            strict,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        let s = &self.state;
+
+        for &val in &[s.a, s.f.bits(), s.b, s.c, s.d, s.e, s.h, s.l,
+                      s.a_, s.f_.bits(), s.b_, s.c_, s.d_, s.e_, s.h_, s.l_, s.i, s.r] {
+            w.u8(val);
+        }
+
+        for &val in &[s.ix, s.iy, s.sp, s.pc] {
+            w.u16(val);
         }
+
+        w.bool(s.iff1);
+        w.bool(s.iff2);
+        w.u8(s.interrupt_mode);
+        w.bool(s.ei_delay);
+        w.bool(s.halted);
+        w.u64(s.cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) {
+        let s = &mut self.state;
+
+        s.a = r.u8();
+        s.f = Flags::from_bits_truncate(r.u8());
+        s.b = r.u8();
+        s.c = r.u8();
+        s.d = r.u8();
+        s.e = r.u8();
+        s.h = r.u8();
+        s.l = r.u8();
+        s.a_ = r.u8();
+        s.f_ = Flags::from_bits_truncate(r.u8());
+        s.b_ = r.u8();
+        s.c_ = r.u8();
+        s.d_ = r.u8();
+        s.e_ = r.u8();
+        s.h_ = r.u8();
+        s.l_ = r.u8();
+        s.i = r.u8();
+        s.r = r.u8();
+
+        s.ix = r.u16();
+        s.iy = r.u16();
+        s.sp = r.u16();
+        s.pc = r.u16();
+
+        s.iff1 = r.bool();
+        s.iff2 = r.bool();
+        s.interrupt_mode = r.u8();
+        s.ei_delay = r.bool();
+        s.halted = r.bool();
+        s.cycles = r.u64();
+    }
+
+    // T-states executed since power on, the master clock for the system
+    pub fn cycles(&self) -> u64 {
+        self.state.cycles
+    }
+
+    // Whether the next step will accept an interrupt rather than execute an
+    // instruction. EI holds interrupts off until the instruction after it
+    // is done.
+    pub fn accepts_interrupt<B: Z80Bus>(&self, b: &B) -> bool {
+        self.state.iff1 && !self.state.ei_delay && b.interrupt_line()
+    }
+
+    // Whether the next step will execute an instruction at PC, rather than
+    // accept an interrupt or idle in HALT
+    pub fn executes_instruction<B: Z80Bus>(&self, b: &B) -> bool {
+        !self.accepts_interrupt(b) && !self.state.halted
     }
 
-    pub fn step(&mut self, b: &mut Bus) {
+    // Executes a single instruction, or accepts a pending interrupt, returning
+    // the number of T-states it took
+    pub fn step<B: Z80Bus>(&mut self, b: &mut B) -> Result<u32, Error> {
+        let start = self.state.cycles;
+        let ei_delay = self.state.ei_delay;
+
+        let interrupt = self.accepts_interrupt(b);
+        self.state.ei_delay = false;
+
+        if interrupt {
+            self.interrupt(b);
+            return Ok((self.state.cycles - start) as u32);
+        }
+
+        if self.state.halted {
+            // HALT runs NOPs until an interrupt arrives
+            self.state.cycles += timing::CYCLES[0] as u64;
+            return Ok((self.state.cycles - start) as u32);
+        }
+
+        let pc = self.state.pc;
         let executor = Executor(&mut self.state, b);
 
-        operations::visit(executor);
+        let fault = match operations::visit(executor) {
+            Ok(()) => None,
+            Err(UnknownOpcode::Illegal(opcode)) if self.strict => Some(Error::IllegalOpcode { pc, opcode }),
+            Err(UnknownOpcode::Illegal(_)) => None,
+            Err(UnknownOpcode::Unimplemented(opcode)) => Some(Error::UnimplementedOpcode { pc, opcode }),
+        };
+
+        if let Some(err) = fault {
+            // Leave the CPU as it was before the instruction that faulted
+            self.state.pc = pc;
+            self.state.cycles = start;
+            self.state.ei_delay = ei_delay;
+            return Err(err);
+        }
+
+        Ok((self.state.cycles - start) as u32)
+    }
+
+    fn interrupt<B: Z80Bus>(&mut self, b: &mut B) {
+        let state = &mut self.state;
+
+        state.iff1 = false;
+        state.iff2 = false;
+        state.halted = false;
+
+        let data = b.acknowledge_interrupt();
+
+        let pc = state.pc;
+        state.push16(b, pc);
+
+        match state.interrupt_mode {
+            2 => {
+                let table = ((state.i as u16) << 8) | data as u16;
+                let lo = state.read8(b, table) as u16;
+                let hi = state.read8(b, table.wrapping_add(1)) as u16;
+
+                state.pc = (hi << 8) | lo;
+                state.cycles += timing::IM2_INTERRUPT as u64;
+            }
+            1 => {
+                state.pc = 0x0038;
+                state.cycles += timing::IM1_INTERRUPT as u64;
+            }
+            _ => {
+                // Only RST instructions are supported on the data bus in
+                // mode 0, which covers every Sega system
+                state.pc = (data & 0x38) as u16;
+                state.cycles += timing::IM1_INTERRUPT as u64;
+            }
+        }
     }
 }
@@ -1,6 +1,7 @@
-use super::io::{Src8, Src16, Dst8, Dst16};
+use super::disassembler::{Cursor, Memory};
+use super::io::{Operand, Src8, Src16, Dst8, Dst16};
 use super::state::{State, Flags};
-use super::super::bus::Bus;
+use super::z80_bus::Z80Bus;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Register8 {
@@ -13,8 +14,14 @@ pub enum Register8 {
     L,
 }
 
+impl Operand for Register8 {
+    fn disassemble<M: Memory + ?Sized>(&self, _: &mut Cursor<M>) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
 impl Src8 for Register8 {
-    fn src8(&self, state: &mut State, _: &mut Bus) -> u8 {
+    fn src8<B: Z80Bus>(&self, state: &mut State, _: &mut B) -> u8 {
         use self::Register8::*;
 
         match *self {
@@ -30,7 +37,7 @@ impl Src8 for Register8 {
 }
 
 impl Dst8 for Register8 {
-    fn dst8(&self, state: &mut State, _: &mut Bus, val: u8) {
+    fn dst8<B: Z80Bus>(&self, state: &mut State, _: &mut B, val: u8) {
         use self::Register8::*;
 
         match *self {
@@ -55,8 +62,14 @@ pub enum Register16 {
     SP,
 }
 
+impl Operand for Register16 {
+    fn disassemble<M: Memory + ?Sized>(&self, _: &mut Cursor<M>) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
 impl Src16 for Register16 {
-    fn src16(&self, state: &mut State, _: &mut Bus) -> u16 {
+    fn src16<B: Z80Bus>(&self, state: &mut State, _: &mut B) -> u16 {
         use self::Register16::*;
 
         match *self {
@@ -70,13 +83,13 @@ impl Src16 for Register16 {
 }
 
 impl Dst16 for Register16 {
-    fn dst16(&self, state: &mut State, _: &mut Bus, value: u16) {
+    fn dst16<B: Z80Bus>(&self, state: &mut State, _: &mut B, value: u16) {
         use self::Register16::*;
 
         match *self {
             AF => {
                 state.a = (value >> 8) as u8;
-                state.f = Flags::from_bits(value as u8).unwrap();
+                state.f = Flags::from_bits_truncate(value as u8);
             }
             BC => {
                 state.b = (value >> 8) as u8;
@@ -98,8 +111,14 @@ impl Dst16 for Register16 {
 #[derive(Debug, Clone, Copy)]
 pub struct Immediate8;
 
+impl Operand for Immediate8 {
+    fn disassemble<M: Memory + ?Sized>(&self, cursor: &mut Cursor<M>) -> String {
+        format!("${:02x}", cursor.next8())
+    }
+}
+
 impl Src8 for Immediate8 {
-    fn src8(&self, state: &mut State, bus: &mut Bus) -> u8 {
+    fn src8<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u8 {
         state.next8(bus)
     }
 }
@@ -107,8 +126,14 @@ impl Src8 for Immediate8 {
 #[derive(Debug, Clone, Copy)]
 pub struct Immediate16;
 
+impl Operand for Immediate16 {
+    fn disassemble<M: Memory + ?Sized>(&self, cursor: &mut Cursor<M>) -> String {
+        format!("${:04x}", cursor.next16())
+    }
+}
+
 impl Src16 for Immediate16 {
-    fn src16(&self, state: &mut State, bus: &mut Bus) -> u16 {
+    fn src16<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u16 {
         state.next16(bus)
     }
 }
@@ -119,6 +144,8 @@ pub enum Address {
     // Relative,
     ImmediateExtended,
 
+    // Not used by any instruction yet
+    #[allow(dead_code)]
     ZeroPage,
 
     BC,
@@ -129,7 +156,7 @@ pub enum Address {
 }
 
 impl Address {
-    pub fn indirect(&self, state: &mut State, bus: &mut Bus) -> u16 {
+    pub fn indirect<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u16 {
         use self::Address::*;
 
         match *self {
@@ -143,19 +170,34 @@ impl Address {
     }
 }
 
+impl Operand for Address {
+    fn disassemble<M: Memory + ?Sized>(&self, cursor: &mut Cursor<M>) -> String {
+        use self::Address::*;
+
+        match *self {
+            Direct => format!("${:04x}", cursor.next16()),
+            ZeroPage => format!("${:02x}", cursor.next8()),
+            ImmediateExtended => format!("(${:04x})", cursor.next16()),
+            BC => "(bc)".to_string(),
+            DE => "(de)".to_string(),
+            HL => "(hl)".to_string(),
+        }
+    }
+}
+
 impl Src8 for Address {
-    fn src8(&self, state: &mut State, bus: &mut Bus) -> u8 {
+    fn src8<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u8 {
         let addr = self.indirect(state, bus);
 
-        bus.read8(addr)
+        state.read8(bus, addr)
     }
 }
 
 impl Dst8 for Address {
-    fn dst8(&self, state: &mut State, bus: &mut Bus, val: u8) {
+    fn dst8<B: Z80Bus>(&self, state: &mut State, bus: &mut B, val: u8) {
         let addr = self.indirect(state, bus);
 
-        bus.write8(addr, val);
+        state.write8(bus, addr, val);
     }
 }
 
@@ -165,33 +207,54 @@ pub enum PortAddress {
 }
 
 impl PortAddress {
-    pub fn indirect(&self, state: &mut State, bus: &mut Bus) -> u8 {
+    // The full address put on the bus, IN A, (n) and OUT (n), A put A on the
+    // high byte and the (C) forms put B there
+    pub fn indirect<B: Z80Bus>(&self, state: &mut State, bus: &mut B) -> u16 {
         use self::PortAddress::*;
 
         match *self {
-            Immediate => state.next8(bus),
-            Indirect => state.c,
+            Immediate => ((state.a as u16) << 8) | state.next8(bus) as u16,
+            Indirect => state.bc(),
+        }
+    }
+}
+
+impl Operand for PortAddress {
+    fn disassemble<M: Memory + ?Sized>(&self, cursor: &mut Cursor<M>) -> String {
+        match *self {
+            PortAddress::Immediate => format!("(${:02x})", cursor.next8()),
+            PortAddress::Indirect => "(c)".to_string(),
         }
     }
 }
 
 pub trait Condition {
     fn check(&self, state: &State) -> bool;
+    // Name of the condition in disassembly, empty when there is none
+    fn mnemonic(&self) -> &'static str;
 }
 
 impl Condition for () {
     fn check(&self, _: &State) -> bool {
         true
     }
+
+    fn mnemonic(&self) -> &'static str {
+        ""
+    }
 }
 
 pub mod condition {
-    #![allow(non_camel_case_types)]
+    #![allow(non_camel_case_types, clippy::upper_case_acronyms)]
     use super::{Flags, State, Condition};
 
     pub struct CARRY;
 
     impl Condition for CARRY {
+        fn mnemonic(&self) -> &'static str {
+            "c"
+        }
+
         fn check(&self, state: &State) -> bool {
             state.f.contains(Flags::C)
         }
@@ -200,6 +263,10 @@ pub mod condition {
     pub struct NON_CARRY;
 
     impl Condition for NON_CARRY {
+        fn mnemonic(&self) -> &'static str {
+            "nc"
+        }
+
         fn check(&self, state: &State) -> bool {
             !state.f.contains(Flags::C)
         }
@@ -208,6 +275,10 @@ pub mod condition {
     pub struct ZERO;
 
     impl Condition for ZERO {
+        fn mnemonic(&self) -> &'static str {
+            "z"
+        }
+
         fn check(&self, state: &State) -> bool {
             state.f.contains(Flags::Z)
         }
@@ -216,6 +287,10 @@ pub mod condition {
     pub struct NON_ZERO;
 
     impl Condition for NON_ZERO {
+        fn mnemonic(&self) -> &'static str {
+            "nz"
+        }
+
         fn check(&self, state: &State) -> bool {
             !state.f.contains(Flags::Z)
         }
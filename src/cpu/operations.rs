@@ -1,9 +1,17 @@
 use super::io::{Src8, Src16, Dst8, Dst16};
 use super::operands::{Register8, Register16, Immediate8, Immediate16, Address, PortAddress, Condition, condition};
+use super::error::Opcode;
 
-pub trait Operations {
-    fn dump_state(&self);
+// Why visit couldn't dispatch an opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownOpcode {
+    // A valid Z80 opcode that hasn't been implemented yet
+    Unimplemented(Opcode),
+    // An opcode the Z80 doesn't define, which the hardware treats as a NOP
+    Illegal(Opcode),
+}
 
+pub trait Operations {
     fn read_opcode(&mut self) -> u8;
     fn read_extended_opcode(&mut self) -> u8;
 
@@ -21,6 +29,8 @@ pub trait Operations {
     fn cp<S: Src8>(&mut self, src: S);
 
     fn disable_interrupts(&mut self);
+    fn enable_interrupts(&mut self);
+    fn halt(&mut self);
     fn set_interrupt_mode(&mut self, interrupt_mode: u8);
 
     fn add16(&mut self, d: Register16, s: Register16);
@@ -31,11 +41,14 @@ pub trait Operations {
     fn jr<C: Condition>(&mut self, cond: C);
     fn call<C: Condition>(&mut self, addr: Address, cond: C);
     fn ret(&mut self);
+    fn reti(&mut self);
+    fn retn(&mut self);
 
     fn out<S: Src8>(&mut self, addr: PortAddress, src: S);
+    fn input<D: Dst8>(&mut self, dst: D, addr: PortAddress);
 }
 
-pub fn visit<O: Operations>(mut ops: O) {
+pub fn visit<O: Operations>(mut ops: O) -> Result<(), UnknownOpcode> {
     use self::Register8::*;
     use self::Register16::*;
 
@@ -79,7 +92,7 @@ pub fn visit<O: Operations>(mut ops: O) {
         0x59 => ops.load8(E, C),
         0x5A => ops.load8(E, D),
         0x5B => ops.load8(E, E),
-        0x5E => ops.load8(C, H),
+        0x5E => ops.load8(E, Address::HL),
         0x5D => ops.load8(E, L),
         0x5F => ops.load8(E, A),
         0x60 => ops.load8(H, B),
@@ -94,7 +107,7 @@ pub fn visit<O: Operations>(mut ops: O) {
         0x69 => ops.load8(L, C),
         0x6A => ops.load8(L, D),
         0x6B => ops.load8(L, E),
-        0x6E => ops.load8(L, H),
+        0x6E => ops.load8(L, Address::HL),
         0x6D => ops.load8(L, L),
         0x6F => ops.load8(L, A),
         0x78 => ops.load8(A, B),
@@ -151,6 +164,8 @@ pub fn visit<O: Operations>(mut ops: O) {
 
         // General purpose arithmetic and CPU control group
         0xf3 => ops.disable_interrupts(),
+        0xfb => ops.enable_interrupts(),
+        0x76 => ops.halt(),
 
         // 16-bit arithmetic group
         0x09 => ops.add16(HL, BC),
@@ -182,14 +197,19 @@ pub fn visit<O: Operations>(mut ops: O) {
 
         // Input and output group
         0xd3 => ops.out(PortAddress::Immediate, A),
+        0xdb => ops.input(A, PortAddress::Immediate),
 
         // extended instructions
-        0xed => visit_ed(ops),
-        _ => panic!("Unrecognised opcode 0x{:02x}", opcode),
+        0xed => return visit_ed(ops),
+        _ => return Err(UnknownOpcode::Unimplemented(Opcode { prefix: None, opcode })),
     }
+
+    Ok(())
 }
 
-pub fn visit_ed<O: Operations>(mut ops: O) {
+pub fn visit_ed<O: Operations>(mut ops: O) -> Result<(), UnknownOpcode> {
+    use self::Register8::*;
+
     let opcode = ops.read_extended_opcode();
 
     match opcode {
@@ -201,6 +221,37 @@ pub fn visit_ed<O: Operations>(mut ops: O) {
         0x46 => ops.set_interrupt_mode(0),
         0x56 => ops.set_interrupt_mode(1),
         0x5E => ops.set_interrupt_mode(2),
-        _ => panic!("Unrecognised extended ED opcode 0x{:02x}", opcode),
+
+        // Call and return group
+        0x4d => ops.reti(),
+        0x45 | 0x55 | 0x5d | 0x65 | 0x6d | 0x75 | 0x7d => ops.retn(),
+
+        // Input and output group
+        0x40 => ops.input(B, PortAddress::Indirect),
+        0x48 => ops.input(C, PortAddress::Indirect),
+        0x50 => ops.input(D, PortAddress::Indirect),
+        0x58 => ops.input(E, PortAddress::Indirect),
+        0x60 => ops.input(H, PortAddress::Indirect),
+        0x68 => ops.input(L, PortAddress::Indirect),
+        0x78 => ops.input(A, PortAddress::Indirect),
+        0x41 => ops.out(PortAddress::Indirect, B),
+        0x49 => ops.out(PortAddress::Indirect, C),
+        0x51 => ops.out(PortAddress::Indirect, D),
+        0x59 => ops.out(PortAddress::Indirect, E),
+        0x61 => ops.out(PortAddress::Indirect, H),
+        0x69 => ops.out(PortAddress::Indirect, L),
+        0x79 => ops.out(PortAddress::Indirect, A),
+        _ => {
+            let opcode = Opcode { prefix: Some(0xed), opcode };
+
+            return match opcode.opcode {
+                0x40..=0x7f | 0xa0..=0xa3 | 0xa8..=0xab | 0xb0..=0xb3 | 0xb8..=0xbb => {
+                    Err(UnknownOpcode::Unimplemented(opcode))
+                }
+                _ => Err(UnknownOpcode::Illegal(opcode)),
+            };
+        }
     }
+
+    Ok(())
 }
@@ -1,4 +1,4 @@
-use super::super::bus::Bus;
+use super::z80_bus::Z80Bus;
 
 bitflags! {
     #[derive(Default)]
@@ -6,12 +6,20 @@ bitflags! {
         const C = 0b00000001;
         const N = 0b00000010;
         const P = 0b00000100;
+        // Undocumented, copies of bits 3 and 5 of the result
+        const X = 0b00001000;
         const H = 0b00010000;
+        const Y = 0b00100000;
         const Z = 0b01000000;
         const S = 0b10000000;
     }
 }
 
+// TODO - check initial values
+// Fields are added as more of the Z80 is emulated, so it can only be built
+// through Default outside this crate
+#[derive(Default, Clone)]
+#[non_exhaustive]
 pub struct State {
     pub a: u8,
     pub f: Flags,
@@ -42,42 +50,12 @@ pub struct State {
     pub iff1: bool,
     pub iff2: bool,
     pub interrupt_mode: u8,
-}
+    // Set by EI, interrupts aren't accepted until the next instruction is done
+    pub ei_delay: bool,
+    pub halted: bool,
 
-impl Default for State {
-    fn default() -> State {
-        State {
-            // TODO - check initial values
-            a: 0,
-            f: Default::default(),
-            b: 0,
-            c: 0,
-            d: 0,
-            e: 0,
-            h: 0,
-            l: 0,
-
-            a_: 0,
-            f_: Default::default(),
-            b_: 0,
-            c_: 0,
-            d_: 0,
-            e_: 0,
-            h_: 0,
-            l_: 0,
-
-            i: 0,
-            r: 0,
-            ix: 0,
-            iy: 0,
-            sp: 0,
-            pc: 0,
-
-            iff1: false,
-            iff2: false,
-            interrupt_mode: 0,
-        }
-    }
+    // T-states executed since power on
+    pub cycles: u64,
 }
 
 impl State {
@@ -108,37 +86,58 @@ impl State {
         self.l = val as u8;
     }
 
-    pub fn next8(&mut self, bus: &Bus) -> u8 {
+    // Memory accesses go through here so the bus can add wait states
+    pub fn read8<B: Z80Bus>(&mut self, bus: &mut B, addr: u16) -> u8 {
+        self.cycles += bus.memory_contention(addr, self.cycles) as u64;
+
+        bus.read8(addr)
+    }
+
+    pub fn fetch8<B: Z80Bus>(&mut self, bus: &mut B, addr: u16) -> u8 {
+        self.cycles += bus.memory_contention(addr, self.cycles) as u64;
+
+        bus.fetch8(addr)
+    }
+
+    pub fn write8<B: Z80Bus>(&mut self, bus: &mut B, addr: u16, val: u8) {
+        self.cycles += bus.memory_contention(addr, self.cycles) as u64;
+
+        bus.write8(addr, val);
+    }
+
+    pub fn next8<B: Z80Bus>(&mut self, bus: &mut B) -> u8 {
         let addr = self.pc;
         self.pc = self.pc.wrapping_add(1);
 
-        bus.read8(addr)
+        self.fetch8(bus, addr)
     }
 
-    pub fn next16(&mut self, bus: &Bus) -> u16 {
+    pub fn next16<B: Z80Bus>(&mut self, bus: &mut B) -> u16 {
         let address = self.pc;
         self.pc = self.pc.wrapping_add(2);
 
-        let lb = bus.read8(address) as u16;
-        let hb = bus.read8(address+1) as u16;
+        let lb = self.fetch8(bus, address) as u16;
+        let hb = self.fetch8(bus, address.wrapping_add(1)) as u16;
 
         (hb << 8) | lb
     }
 
-    pub fn push8(&mut self, bus: &mut Bus, val: u8) {
+    pub fn push8<B: Z80Bus>(&mut self, bus: &mut B, val: u8) {
         self.sp = self.sp.wrapping_sub(1);
 
-        bus.write8(self.sp, val);
+        let sp = self.sp;
+        self.write8(bus, sp, val);
     }
 
-    pub fn pop8(&mut self, bus: &mut Bus) -> u8 {
-        let val = bus.read8(self.sp);
+    pub fn pop8<B: Z80Bus>(&mut self, bus: &mut B) -> u8 {
+        let sp = self.sp;
+        let val = self.read8(bus, sp);
         self.sp = self.sp.wrapping_add(1);
 
         val
     }
 
-    pub fn push16(&mut self, bus: &mut Bus, val: u16) {
+    pub fn push16<B: Z80Bus>(&mut self, bus: &mut B, val: u16) {
         let lsb = val as u8;
         let msb = (val >> 8) as u8;
 
@@ -146,7 +145,7 @@ impl State {
         self.push8(bus, lsb);
     }
 
-    pub fn pop16(&mut self, bus: &mut Bus) -> u16 {
+    pub fn pop16<B: Z80Bus>(&mut self, bus: &mut B) -> u16 {
         let lsb = self.pop8(bus) as u16;
         let msb = self.pop8(bus) as u16;
 
@@ -0,0 +1,43 @@
+// Everything the Z80 needs from the machine it's in. Implement this to run
+// the core in something other than a Master System.
+pub trait Z80Bus {
+    fn read8(&mut self, addr: u16) -> u8;
+
+    // Reads an opcode or operand at PC, for machines that tell instruction
+    // fetches apart from data reads
+    fn fetch8(&mut self, addr: u16) -> u8 {
+        self.read8(addr)
+    }
+
+    fn write8(&mut self, addr: u16, val: u8);
+
+    // Port accesses see the full 16-bit address the Z80 puts on the bus,
+    // the high byte is A or B depending on the instruction
+    fn in8(&mut self, port: u16) -> u8;
+    fn out8(&mut self, port: u16, val: u8);
+
+    // Level of the INT line, true while an interrupt is being requested
+    fn interrupt_line(&self) -> bool;
+
+    // The byte the interrupting device puts on the data bus when the CPU
+    // acknowledges an interrupt. Mode 0 executes it as an RST, mode 2 uses
+    // it as the low byte of the vector address.
+    fn acknowledge_interrupt(&mut self) -> u8 {
+        0xff
+    }
+
+    // Called with the CPU's T-state count before each port access, so the
+    // rest of the machine can be brought up to date with it
+    fn advance_to(&mut self, _cycles: u64) {}
+
+    // Wait states to add to a memory or port access, for machines where the
+    // CPU has to wait on other hardware. cycles is the CPU's T-state count
+    // at the end of the instruction making the access.
+    fn memory_contention(&mut self, _addr: u16, _cycles: u64) -> u32 {
+        0
+    }
+
+    fn io_contention(&mut self, _port: u16, _cycles: u64) -> u32 {
+        0
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use caduceus::cpu::{disassemble, Flags, Instruction, Memory, State};
+use caduceus::{Access, Error, Hit, StateFormat, Symbols, VM};
+
+use print_console;
+
+const HELP: &str = "\
+Numbers are in hex, with an optional $ or 0x prefix. Addresses can also be
+given as labels from the symbol file.
+
+  c, continue              run until a breakpoint or watchpoint
+  s, step [count]          execute instructions
+  n, next                  step over calls
+  finish                   run until the current routine returns
+  b, break <addr>          break when PC reaches addr, a label only in its bank
+  d, delete <addr>         remove a breakpoint
+  watch [r|w|rw] <addr>    break on memory reads and/or writes
+  unwatch [r|w|rw] <addr>  remove a memory watchpoint
+  io [in|out] <port>       break on port accesses, both directions by default
+  unio [in|out] <port>     remove a port breakpoint
+  info                     list breakpoints and watchpoints
+  r, regs                  show the CPU registers
+  set <reg> <value>        change a register
+  x <addr> [len]           dump memory
+  poke <addr> <byte>...    write bytes to memory
+  dis [addr] [count]       disassemble, around PC by default
+  vdp                      show the VDP registers
+  vram <addr> [len]        dump VRAM
+  cram                     dump colour RAM
+  save [slot]              save the machine state to a slot, 0-9
+  load [slot]              load the machine state from a slot, 0-9
+  rewind [frames]          go back frames, needs --rewind
+  import <file>            load a state file, caduceus's own or Meka's .S00
+  export <file>            write a state file, in Meka's format for .S00-.S09
+  q, quit                  exit
+An empty line repeats the last command.";
+
+// Number of save state slots, each is a file next to the ROM
+pub const STATE_SLOTS: u8 = 10;
+
+// The file a save state slot is kept in, game.sms keeps slot 3 in game.state3
+pub fn state_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("state{}", slot))
+}
+
+// Meka numbers its states .S00 to .S09, anything else is written in
+// caduceus's own format
+fn state_format(path: &str) -> StateFormat {
+    let extension = Path::new(path).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some(extension) if extension.len() == 3 && extension.starts_with("s0") => StateFormat::Meka,
+        _ => StateFormat::Caduceus,
+    }
+}
+
+// Why execution stopped
+enum Stop {
+    Breakpoint,
+    Watchpoint(Hit),
+    Fault(Error),
+    Done,
+}
+
+pub struct Debugger {
+    vm: VM,
+    symbols: Symbols,
+    // Addresses to stop at, with the ROM bank that has to be mapped there
+    // for the ones set by label
+    breakpoints: BTreeSet<(u16, Option<usize>)>,
+    // The ROM the save state slots belong to
+    rom: PathBuf,
+}
+
+impl Debugger {
+    pub fn new<P: AsRef<Path>>(vm: VM, symbols: Symbols, rom: P) -> Self {
+        Debugger {
+            vm,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            rom: rom.as_ref().to_path_buf(),
+        }
+    }
+
+    // Reads commands from stdin until quit or end of input
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut last = String::new();
+
+        self.show_location();
+
+        loop {
+            print!("(caduceus) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            let line = if line.trim().is_empty() { last.clone() } else { line.trim().to_string() };
+            last = line.clone();
+
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            if args.is_empty() {
+                continue;
+            }
+
+            if args[0] == "q" || args[0] == "quit" {
+                break;
+            }
+
+            let result = self.command(&args);
+            print_console(&mut self.vm);
+
+            if let Err(message) = result {
+                println!("{}", message);
+            }
+        }
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<(), String> {
+        match args[0] {
+            "h" | "help" => println!("{}", HELP),
+            "c" | "continue" => {
+                let stop = self.run_until(|_, _, _| false);
+                self.report(stop);
+            }
+            "s" | "step" => {
+                let count = args.get(1).map_or(Ok(1), |arg| parse(arg))?;
+                if count == 0 {
+                    return Err("The count has to be at least 1".to_string());
+                }
+
+                let mut remaining = count;
+                let stop = self.run_until(|_, _, _| {
+                    remaining -= 1;
+                    remaining == 0
+                });
+                self.report(stop);
+            }
+            "n" | "next" => {
+                let stop = self.step_over();
+                self.report(stop);
+            }
+            "finish" => {
+                let stop = self.step_out();
+                self.report(stop);
+            }
+            "b" | "break" => {
+                let breakpoint = self.location(arg(args, 1)?)?;
+                self.breakpoints.insert(breakpoint);
+            }
+            "d" | "delete" => {
+                let breakpoint = self.location(arg(args, 1)?)?;
+                self.breakpoints.remove(&breakpoint);
+            }
+            "watch" | "unwatch" | "io" | "unio" => self.watch(args)?,
+            "info" => self.info(),
+            "r" | "regs" => show_registers(self.vm.cpu().state()),
+            "set" => {
+                let value = parse(arg(args, 2)?)?;
+                set_register(self.vm.cpu_mut().state_mut(), arg(args, 1)?, value)?;
+            }
+            "x" => {
+                let addr = self.address(arg(args, 1)?)?;
+                let len = args.get(2).map_or(Ok(0x40), |arg| parse(arg))?;
+                let vm = &self.vm;
+
+                dump(addr as usize, len as usize, |addr| vm.peek8(addr as u16));
+            }
+            "poke" => {
+                let addr = self.address(arg(args, 1)?)?;
+                let bytes = args[2..].iter().map(|arg| parse(arg)).collect::<Result<Vec<_>, _>>()?;
+
+                for (i, &byte) in bytes.iter().enumerate() {
+                    self.vm.poke8(addr.wrapping_add(i as u16), byte as u8);
+                }
+            }
+            "dis" => {
+                let pc = self.vm.cpu().state().pc;
+                let addr = args.get(1).map_or(Ok(None), |arg| self.address(arg).map(Some))?;
+                let count = args.get(2).map_or(Ok(10), |arg| parse(arg))?;
+
+                self.disassemble(addr.unwrap_or_else(|| self.start_before(pc)), count as usize);
+            }
+            "vdp" => {
+                for (i, reg) in self.vm.vdp_registers().iter().enumerate() {
+                    println!("R{:<2} {:02x}", i, reg);
+                }
+                println!("Status {:02x}  line {}  V counter {:02x}",
+                         self.vm.vdp_status(), self.vm.scanline(), self.vm.v_counter());
+            }
+            "vram" => {
+                let addr = parse(arg(args, 1)?)? as usize;
+                let len = args.get(2).map_or(Ok(0x40), |arg| parse(arg))?;
+                let vram = self.vm.vram();
+
+                dump(addr, len as usize, |addr| vram[addr & 0x3fff]);
+            }
+            "cram" => {
+                let cram = self.vm.cram();
+
+                dump(0, cram.len(), |addr| cram[addr]);
+            }
+            "save" => {
+                let path = self.state_slot(args)?;
+
+                fs::write(&path, self.vm.save_state())
+                    .map_err(|err| format!("Failed to save {}: {}", path.display(), err))?;
+                println!("Saved {}", path.display());
+            }
+            "load" => {
+                let path = self.state_slot(args)?;
+                let state = fs::read(&path)
+                    .map_err(|err| format!("Failed to load {}: {}", path.display(), err))?;
+
+                self.vm.load_state(&state).map_err(|err| err.to_string())?;
+                self.show_location();
+            }
+            "rewind" => {
+                let count = args.get(1).map_or(Ok(1), |arg| parse(arg))?;
+
+                if self.vm.rewind().is_none() {
+                    return Err("Rewinding is off, start with --rewind MB".to_string());
+                }
+
+                for _ in 0..count {
+                    if !self.vm.rewind_frame().map_err(|err| err.to_string())? {
+                        println!("Reached the oldest frame kept");
+                        break;
+                    }
+                }
+                self.show_location();
+            }
+            "import" => {
+                let path = arg(args, 1)?;
+                let state = fs::read(path).map_err(|err| format!("Failed to load {}: {}", path, err))?;
+
+                self.vm.load_state(&state).map_err(|err| err.to_string())?;
+                self.show_location();
+            }
+            "export" => {
+                let path = arg(args, 1)?;
+                let state = self.vm.export_state(state_format(path)).map_err(|err| err.to_string())?;
+
+                fs::write(path, state).map_err(|err| format!("Failed to save {}: {}", path, err))?;
+                println!("Saved {}", path);
+            }
+            _ => return Err(format!("Unknown command {}, try help", args[0])),
+        }
+
+        Ok(())
+    }
+
+    // The file for the slot given to save or load, slot 0 by default
+    fn state_slot(&self, args: &[&str]) -> Result<PathBuf, String> {
+        let slot = match args.get(1) {
+            Some(arg) => arg.parse::<u8>().map_err(|_| format!("Bad slot {}", arg))?,
+            None => 0,
+        };
+
+        if slot >= STATE_SLOTS {
+            return Err(format!("Slots are 0-{}", STATE_SLOTS - 1));
+        }
+
+        Ok(state_path(&self.rom, slot))
+    }
+
+    // Steps until done returns true, or a breakpoint, watchpoint or fault
+    // stops execution. done sees the machine after each step, along with the
+    // CPU state before and after it. The
+    // breakpoint at the starting PC is skipped so execution can move on
+    // from it.
+    fn run_until<F: FnMut(&VM, &State, &State) -> bool>(&mut self, mut done: F) -> Stop {
+        self.vm.watchpoints_mut().take_hit();
+
+        loop {
+            let before = self.vm.cpu().state().clone();
+
+            if let Err(err) = self.vm.step_instruction() {
+                return Stop::Fault(err);
+            }
+
+            if let Some(hit) = self.vm.watchpoints_mut().take_hit() {
+                return Stop::Watchpoint(hit);
+            }
+
+            let after = self.vm.cpu().state();
+
+            if done(&self.vm, &before, after) {
+                return Stop::Done;
+            }
+
+            if self.is_breakpoint(after.pc) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    // Runs a call or block instruction to completion, anything else is a
+    // single step
+    fn step_over(&mut self) -> Stop {
+        let state = self.vm.cpu().state().clone();
+        let instruction = disassemble(&self.vm, state.pc);
+
+        let over = ["call", "rst", "ldir", "lddr", "cpir", "cpdr", "inir", "indr", "otir", "otdr", "halt"]
+            .iter()
+            .any(|mnemonic| instruction.text.split(' ').next() == Some(mnemonic));
+
+        if !over {
+            return self.run_until(|_, _, _| true);
+        }
+
+        let next = instruction.next_addr();
+        self.run_until(|_, _, after| after.pc == next && after.sp >= state.sp)
+    }
+
+    // Runs until a return leaves the current routine's stack frame. Pops and
+    // SP arithmetic inside the routine don't count, only a return taking SP
+    // above where it was.
+    fn step_out(&mut self) -> Stop {
+        let sp = self.vm.cpu().state().sp;
+
+        self.run_until(|vm, before, after| after.sp > sp && is_return(vm, before.pc))
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint => println!("Breakpoint"),
+            Stop::Watchpoint(hit) => match hit.access {
+                Access::Read | Access::Write => {
+                    println!("Watchpoint: {:?} {:04x} = {:02x}", hit.access, hit.addr, hit.val);
+                }
+                Access::In | Access::Out => {
+                    println!("Watchpoint: {:?} port {:02x} = {:02x}", hit.access, hit.addr as u8, hit.val);
+                }
+            },
+            Stop::Fault(err) => println!("{}", err),
+            Stop::Done => {}
+        }
+
+        self.show_location();
+    }
+
+    fn show_location(&self) {
+        let state = self.vm.cpu().state();
+        let instruction = disassemble(&self.vm, state.pc);
+        let text = self.annotate(&instruction);
+
+        self.show_label(state.pc);
+
+        if state.halted {
+            println!("{:04x}: {} (halted)", state.pc, text);
+        } else {
+            println!("{:04x}: {}", state.pc, text);
+        }
+    }
+
+    fn show_label(&self, addr: u16) {
+        if let Some(label) = self.symbols.label(&self.vm, addr) {
+            println!("{}:", label);
+        }
+    }
+
+    fn annotate(&self, instruction: &Instruction) -> String {
+        self.symbols.annotate(&self.vm, instruction)
+    }
+
+    // A label, or an address in hex
+    fn address(&self, arg: &str) -> Result<u16, String> {
+        self.location(arg).map(|(addr, _)| addr)
+    }
+
+    // A label and the bank it's in, or an address in hex in any bank
+    fn location(&self, arg: &str) -> Result<(u16, Option<usize>), String> {
+        match self.symbols.resolve(arg) {
+            Some(location) => Ok(location),
+            None => parse(arg).map(|addr| (addr as u16, None)),
+        }
+    }
+
+    fn is_breakpoint(&self, addr: u16) -> bool {
+        let bank = self.vm.bank(addr);
+
+        self.breakpoints.range((addr, None)..=(addr, Some(usize::MAX)))
+            .any(|&(_, breakpoint_bank)| breakpoint_bank.is_none() || breakpoint_bank == bank)
+    }
+
+    fn info(&self) {
+        for &(addr, bank) in &self.breakpoints {
+            let label = self.symbols.lookup(addr, bank);
+
+            match (label, bank) {
+                (Some(label), Some(bank)) => println!("break {:04x} ({}, bank {})", addr, label, bank),
+                (Some(label), None) => println!("break {:04x} ({})", addr, label),
+                (None, _) => println!("break {:04x}", addr),
+            }
+        }
+
+        for (access, addr) in self.vm.watchpoints().list() {
+            match access {
+                Access::Read => println!("watch r {:04x}", addr),
+                Access::Write => println!("watch w {:04x}", addr),
+                Access::In => println!("io in {:02x}", addr),
+                Access::Out => println!("io out {:02x}", addr),
+            }
+        }
+    }
+
+    fn watch(&mut self, args: &[&str]) -> Result<(), String> {
+        let remove = args[0].starts_with("un");
+        let memory = args[0].ends_with("watch");
+
+        let (kind, addr) = match args.len() {
+            2 => (if memory { "rw" } else { "inout" }, args[1]),
+            3 => (args[1], args[2]),
+            _ => return Err(format!("Usage: {} [kind] <addr>", args[0])),
+        };
+        let addr = self.address(addr)?;
+
+        let accesses = match (memory, kind) {
+            (true, "r") => vec![Access::Read],
+            (true, "w") => vec![Access::Write],
+            (true, "rw") => vec![Access::Read, Access::Write],
+            (false, "in") => vec![Access::In],
+            (false, "out") => vec![Access::Out],
+            (false, "inout") => vec![Access::In, Access::Out],
+            _ => return Err(format!("Unknown kind {}", kind)),
+        };
+
+        let watchpoints = self.vm.watchpoints_mut();
+        for access in accesses {
+            if remove {
+                watchpoints.remove(access, addr);
+            } else {
+                watchpoints.add(access, addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Finds an address a few instructions before pc that decodes into a run
+    // of instructions landing exactly on pc
+    fn start_before(&self, pc: u16) -> u16 {
+        let vm = &self.vm;
+
+        for back in (1..=12u16).rev() {
+            let start = pc.wrapping_sub(back);
+            let mut addr = start;
+            let mut count = 0;
+            let mut valid = true;
+
+            while addr.wrapping_sub(start) < back {
+                let instruction = disassemble(vm, addr);
+
+                valid &= !instruction.text.starts_with("db ");
+                addr = instruction.next_addr();
+                count += 1;
+            }
+
+            if valid && addr == pc && count <= 4 {
+                return start;
+            }
+        }
+
+        pc
+    }
+
+    fn disassemble(&self, mut addr: u16, count: usize) {
+        let vm = &self.vm;
+        let pc = vm.cpu().state().pc;
+
+        for _ in 0..count {
+            let instruction = disassemble(vm, addr);
+            let bytes = instruction.bytes.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let marker = if addr == pc { ">" } else { " " };
+            let breakpoint = if self.is_breakpoint(addr) { "*" } else { " " };
+
+            self.show_label(addr);
+            println!("{}{} {:04x}  {:<12}{}", marker, breakpoint, addr, bytes, self.annotate(&instruction));
+            addr = instruction.next_addr();
+        }
+    }
+}
+
+// ret, ret cc, reti and retn, including retn's undocumented copies
+fn is_return<M: Memory>(memory: &M, pc: u16) -> bool {
+    match memory.peek8(pc) {
+        0xc9 => true,
+        op if op & 0xc7 == 0xc0 => true,
+        0xed => memory.peek8(pc.wrapping_add(1)) & 0xc7 == 0x45,
+        _ => false,
+    }
+}
+
+fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i).cloned().ok_or_else(|| format!("{} needs more arguments, try help", args[0]))
+}
+
+fn parse(arg: &str) -> Result<u32, String> {
+    let digits = arg.trim_start_matches('$').trim_start_matches("0x");
+
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Bad number {}", arg))
+}
+
+// Hex dump with 16 bytes a line
+fn dump<F: Fn(usize) -> u8>(start: usize, len: usize, read: F) {
+    for line in (0..len).step_by(16) {
+        let bytes = (line..(line + 16).min(len))
+            .map(|i| read(start + i))
+            .collect::<Vec<_>>();
+
+        let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+        let text = bytes.iter()
+            .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+            .collect::<String>();
+
+        println!("{:04x}  {:<48} {}", start + line, hex, text);
+    }
+}
+
+fn show_registers(state: &State) {
+    let flags = [(Flags::S, 'S'), (Flags::Z, 'Z'), (Flags::Y, 'Y'), (Flags::H, 'H'),
+                 (Flags::X, 'X'), (Flags::P, 'P'), (Flags::N, 'N'), (Flags::C, 'C')]
+        .iter()
+        .map(|&(flag, name)| if state.f.contains(flag) { name } else { '-' })
+        .collect::<String>();
+
+    println!("AF  {:02x}{:02x}  BC  {:04x}  DE  {:04x}  HL  {:04x}  [{}]",
+             state.a, state.f.bits(), state.bc(), state.de(), state.hl(), flags);
+    println!("AF' {:02x}{:02x}  BC' {:02x}{:02x}  DE' {:02x}{:02x}  HL' {:02x}{:02x}",
+             state.a_, state.f_.bits(), state.b_, state.c_, state.d_, state.e_, state.h_, state.l_);
+    println!("IX  {:04x}  IY  {:04x}  SP  {:04x}  PC  {:04x}", state.ix, state.iy, state.sp, state.pc);
+    println!("I {:02x}  R {:02x}  IM {}  IFF1 {}  IFF2 {}  cycles {}",
+             state.i, state.r, state.interrupt_mode, state.iff1 as u8, state.iff2 as u8, state.cycles);
+}
+
+fn set_register(state: &mut State, name: &str, value: u32) -> Result<(), String> {
+    let byte = value as u8;
+    let word = value as u16;
+
+    match name.to_lowercase().as_str() {
+        "a" => state.a = byte,
+        "f" => state.f = Flags::from_bits_truncate(byte),
+        "b" => state.b = byte,
+        "c" => state.c = byte,
+        "d" => state.d = byte,
+        "e" => state.e = byte,
+        "h" => state.h = byte,
+        "l" => state.l = byte,
+        "i" => state.i = byte,
+        "r" => state.r = byte,
+        "af" => {
+            state.a = (word >> 8) as u8;
+            state.f = Flags::from_bits_truncate(word as u8);
+        }
+        "bc" => state.set_bc(word),
+        "de" => state.set_de(word),
+        "hl" => state.set_hl(word),
+        "ix" => state.ix = word,
+        "iy" => state.iy = word,
+        "sp" => state.sp = word,
+        "pc" => state.pc = word,
+        _ => return Err(format!("Unknown register {}", name)),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use bus::Slot;
+use cartridge::MapperType;
+use cpu::{self, Opcode};
+use savestate::StateFormat;
+
+// New variants may be added as the emulator grows
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // A ROM, BIOS or card image couldn't be read
+    RomLoad { path: String, source: io::Error },
+    // Nothing to boot, no cartridge, card, expansion or BIOS was given
+    NoMedia,
+    // The cartridge header checksum doesn't match, only checked in strict mode
+    BadHeader { checksum: u16, calculated_checksum: u16 },
+    // The mapper can't address the whole ROM
+    UnsupportedMapper { mapper: MapperType, rom_size: usize },
+    // An opcode the CPU doesn't implement yet
+    UnimplementedOpcode { pc: u16, opcode: Opcode },
+    // An opcode that isn't defined on the Z80, only reported in strict mode
+    IllegalOpcode { pc: u16, opcode: Opcode },
+    // The instruction trace couldn't be written
+    Trace { source: io::Error },
+    // The data isn't a save state this version of caduceus can load
+    InvalidState { reason: &'static str },
+    // The save state was made with different media in a slot
+    StateMediaMismatch { slot: Slot },
+    // A save state from another emulator that can't be converted
+    UnsupportedStateFormat { format: StateFormat },
+    // The data isn't a movie this version of caduceus can play
+    InvalidMovie { reason: &'static str },
+    // The movie was recorded with different media or settings, so playing
+    // it would desync
+    MovieMismatch { setting: &'static str },
+    // The movie starts at power on, but the machine has already run
+    MovieNeedsPowerOn,
+    // A line of a regression manifest couldn't be read
+    InvalidManifest { line: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::RomLoad { ref path, ref source } => write!(f, "Failed to load {}: {}", path, source),
+            Error::NoMedia => write!(f, "No cartridge, card or BIOS to boot"),
+            Error::BadHeader { checksum, calculated_checksum } => {
+                write!(f, "Cartridge checksum 0x{:04x} doesn't match calculated 0x{:04x}",
+                       checksum, calculated_checksum)
+            }
+            Error::UnsupportedMapper { mapper, rom_size } => {
+                write!(f, "{:?} mapper can't address a ROM of 0x{:x} bytes", mapper, rom_size)
+            }
+            Error::UnimplementedOpcode { pc, opcode } => {
+                write!(f, "Unimplemented opcode {} at 0x{:04x}", opcode, pc)
+            }
+            Error::IllegalOpcode { pc, opcode } => write!(f, "Illegal opcode {} at 0x{:04x}", opcode, pc),
+            Error::Trace { ref source } => write!(f, "Failed to write trace: {}", source),
+            Error::InvalidState { reason } => write!(f, "Invalid save state: {}", reason),
+            Error::StateMediaMismatch { slot } => {
+                write!(f, "Save state was made with different media in the {:?} slot", slot)
+            }
+            Error::UnsupportedStateFormat { format } => write!(f, "{:?} save states aren't supported", format),
+            Error::InvalidMovie { reason } => write!(f, "Invalid movie: {}", reason),
+            Error::MovieMismatch { setting } => write!(f, "Movie was recorded with a different {}", setting),
+            Error::MovieNeedsPowerOn => write!(f, "Movie starts at power on, but the machine has already run"),
+            Error::InvalidManifest { line } => write!(f, "Invalid manifest at line {}", line),
+        }
+    }
+}
+
+impl From<cpu::Error> for Error {
+    fn from(err: cpu::Error) -> Self {
+        match err {
+            cpu::Error::UnimplementedOpcode { pc, opcode } => Error::UnimplementedOpcode { pc, opcode },
+            cpu::Error::IllegalOpcode { pc, opcode } => Error::IllegalOpcode { pc, opcode },
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::RomLoad { ref source, .. } => Some(source),
+            Error::Trace { ref source } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use caduceus::cpu::{Flags, Memory, State};
+use caduceus::{Access, Hit, VM};
+
+// Register layout reported to GDB, the same as GDB's own z80 target
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="ix" bitsize="16" type="data_ptr"/>
+    <reg name="iy" bitsize="16" type="data_ptr"/>
+    <reg name="af'" bitsize="16" type="int"/>
+    <reg name="bc'" bitsize="16" type="int"/>
+    <reg name="de'" bitsize="16" type="int"/>
+    <reg name="hl'" bitsize="16" type="int"/>
+    <reg name="ir" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 13;
+
+// Instructions run between checks for an interrupt from GDB while continuing
+const INTERRUPT_POLL: usize = 10000;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// A GDB remote serial protocol server, debugging the VM over TCP
+pub struct GdbStub {
+    vm: VM,
+    breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(vm: VM) -> Self {
+        GdbStub {
+            vm,
+            breakpoints: BTreeSet::new(),
+            no_ack: false,
+        }
+    }
+
+    // Waits for GDB to connect on the local port, then serves it until it
+    // detaches or disconnects
+    pub fn serve(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on port {}", port);
+
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        match self.session(stream) {
+            Err(ref err) if disconnected(err) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn session(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        while let Some(packet) = read_packet(&mut reader)? {
+            if !self.no_ack {
+                writer.write_all(b"+")?;
+            }
+
+            let reply = match self.handle(&packet, &mut reader) {
+                Some(reply) => reply,
+                None => break,
+            };
+
+            write_packet(&mut writer, &reply)?;
+
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reply to a packet, None once GDB is done with us
+    fn handle(&mut self, packet: &str, reader: &mut BufReader<TcpStream>) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP, None),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => self.resume(args, reader, false),
+            "s" => self.resume(args, reader, true),
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => "OK".to_string(),
+            "D" => {
+                self.reply_and_close(reader);
+                return None;
+            }
+            "k" => return None,
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    fn reply_and_close(&mut self, reader: &mut BufReader<TcpStream>) {
+        let _ = write_packet(reader.get_mut(), "OK");
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = match parse_pair(range, ',') {
+                Some(pair) => pair,
+                None => return "E01".to_string(),
+            };
+
+            let data = TARGET_XML.as_bytes();
+            let start = (offset as usize).min(data.len());
+            let end = (start + len as usize).min(data.len());
+            let prefix = if end == data.len() { "l" } else { "m" };
+
+            format!("{}{}", prefix, String::from_utf8_lossy(&data[start..end]))
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let state = self.vm.cpu().state();
+
+        (0..REGISTER_COUNT)
+            .map(|i| hex16(register(state, i)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let values = match decode_hex(args) {
+            Some(ref bytes) if bytes.len() >= REGISTER_COUNT * 2 => bytes.clone(),
+            _ => return "E01".to_string(),
+        };
+
+        let state = self.vm.cpu_mut().state_mut();
+        for i in 0..REGISTER_COUNT {
+            let value = values[i * 2] as u16 | ((values[i * 2 + 1] as u16) << 8);
+            set_register(state, i, value);
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match u32::from_str_radix(args, 16) {
+            Ok(i) if (i as usize) < REGISTER_COUNT => hex16(register(self.vm.cpu().state(), i as usize)),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let index = parts.next().and_then(|i| usize::from_str_radix(i, 16).ok());
+        let value = parts.next().and_then(decode_hex);
+
+        match (index, value) {
+            (Some(i), Some(ref bytes)) if i < REGISTER_COUNT && bytes.len() == 2 => {
+                let value = bytes[0] as u16 | ((bytes[1] as u16) << 8);
+                set_register(self.vm.cpu_mut().state_mut(), i, value);
+
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_pair(args, ',') {
+            Some(pair) => pair,
+            None => return "E01".to_string(),
+        };
+
+        (0..len)
+            .map(|i| format!("{:02x}", self.vm.peek8((addr + i) as u16)))
+            .collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(|range| parse_pair(range, ','));
+        let data = parts.next().and_then(decode_hex);
+
+        match (range, data) {
+            (Some((addr, len)), Some(data)) if data.len() == len as usize => {
+                for (i, &byte) in data.iter().enumerate() {
+                    self.vm.poke8((addr as usize + i) as u16, byte);
+                }
+
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Z0 and Z1 are software and hardware breakpoints, which both just watch
+    // PC since ROM can't be patched. Z2-Z4 are write, read and access
+    // watchpoints over a range of addresses.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|addr| u32::from_str_radix(addr, 16).ok());
+        let len = parts.next().and_then(|len| u32::from_str_radix(len, 16).ok()).unwrap_or(1);
+
+        let addr = match addr {
+            Some(addr) => addr as u16,
+            None => return "E01".to_string(),
+        };
+
+        let accesses: &[Access] = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+
+                return "OK".to_string();
+            }
+            Some("2") => &[Access::Write],
+            Some("3") => &[Access::Read],
+            Some("4") => &[Access::Read, Access::Write],
+            _ => return String::new(),
+        };
+
+        let watchpoints = self.vm.watchpoints_mut();
+        for offset in 0..len.max(1) {
+            let addr = addr.wrapping_add(offset as u16);
+
+            for &access in accesses {
+                if insert {
+                    watchpoints.add(access, addr);
+                } else {
+                    watchpoints.remove(access, addr);
+                }
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    // Continues or single steps, optionally from a new address
+    fn resume(&mut self, args: &str, reader: &mut BufReader<TcpStream>, step: bool) -> String {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.vm.cpu_mut().state_mut().pc = addr;
+        }
+
+        self.vm.watchpoints_mut().take_hit();
+        let mut count = 0;
+
+        loop {
+            if self.vm.step_instruction().is_err() {
+                return stop_reply(SIGILL, None);
+            }
+
+            if let Some(hit) = self.vm.watchpoints_mut().take_hit() {
+                return stop_reply(SIGTRAP, Some(hit));
+            }
+
+            if step || self.breakpoints.contains(&self.vm.cpu().state().pc) {
+                return stop_reply(SIGTRAP, None);
+            }
+
+            count += 1;
+            if count % INTERRUPT_POLL == 0 && interrupted(reader) {
+                return stop_reply(SIGINT, None);
+            }
+        }
+    }
+}
+
+// GDB register i, in the order of TARGET_XML
+fn register(state: &State, i: usize) -> u16 {
+    let pair = |hi: u8, lo: u8| ((hi as u16) << 8) | lo as u16;
+
+    match i {
+        0 => pair(state.a, state.f.bits()),
+        1 => state.bc(),
+        2 => state.de(),
+        3 => state.hl(),
+        4 => state.sp,
+        5 => state.pc,
+        6 => state.ix,
+        7 => state.iy,
+        8 => pair(state.a_, state.f_.bits()),
+        9 => pair(state.b_, state.c_),
+        10 => pair(state.d_, state.e_),
+        11 => pair(state.h_, state.l_),
+        _ => pair(state.i, state.r),
+    }
+}
+
+fn set_register(state: &mut State, i: usize, value: u16) {
+    let hi = (value >> 8) as u8;
+    let lo = value as u8;
+
+    match i {
+        0 => {
+            state.a = hi;
+            state.f = Flags::from_bits_truncate(lo);
+        }
+        1 => state.set_bc(value),
+        2 => state.set_de(value),
+        3 => state.set_hl(value),
+        4 => state.sp = value,
+        5 => state.pc = value,
+        6 => state.ix = value,
+        7 => state.iy = value,
+        8 => {
+            state.a_ = hi;
+            state.f_ = Flags::from_bits_truncate(lo);
+        }
+        9 => {
+            state.b_ = hi;
+            state.c_ = lo;
+        }
+        10 => {
+            state.d_ = hi;
+            state.e_ = lo;
+        }
+        11 => {
+            state.h_ = hi;
+            state.l_ = lo;
+        }
+        _ => {
+            state.i = hi;
+            state.r = lo;
+        }
+    }
+}
+
+fn stop_reply(signal: u8, hit: Option<Hit>) -> String {
+    match hit {
+        Some(Hit { access: Access::Write, addr, .. }) => format!("T{:02x}watch:{:04x};", signal, addr),
+        Some(Hit { access: Access::Read, addr, .. }) => format!("T{:02x}rwatch:{:04x};", signal, addr),
+        _ => format!("S{:02x}", signal),
+    }
+}
+
+// GDB going away without detaching isn't an error
+fn disconnected(err: &io::Error) -> bool {
+    matches!(err.kind(),
+             io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted |
+             io::ErrorKind::UnexpectedEof)
+}
+
+// Checks, without blocking, whether GDB has sent a break (Ctrl-C)
+fn interrupted(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.get_ref().set_nonblocking(true).is_err() {
+        return false;
+    }
+
+    let mut byte = [0];
+    let interrupted = matches!(reader.read(&mut byte), Ok(1) if byte[0] == 0x03);
+
+    let _ = reader.get_ref().set_nonblocking(false);
+
+    interrupted
+}
+
+// Reads the next packet, skipping acknowledgements. A break received while
+// stopped is answered as though it were a status query.
+fn read_packet<R: Read>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut byte = [0];
+
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        match byte[0] {
+            b'$' => break,
+            0x03 => return Ok(Some("?".to_string())),
+            _ => {}
+        }
+    }
+
+    let mut data = Vec::new();
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        if byte[0] == b'#' {
+            break;
+        }
+
+        data.push(byte[0]);
+    }
+
+    // The checksum isn't checked, TCP already makes sure the data arrives
+    let mut checksum = [0; 2];
+    reader.read_exact(&mut checksum)?;
+
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+
+    write!(writer, "${}#{:02x}", data, checksum)?;
+    writer.flush()
+}
+
+// Registers go over the wire little endian
+fn hex16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, (value >> 8) as u8)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_pair(args: &str, separator: char) -> Option<(u32, u32)> {
+    let mut parts = args.splitn(2, separator);
+    let first = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let second = u32::from_str_radix(parts.next()?, 16).ok()?;
+
+    Some((first, second))
+}
@@ -0,0 +1,100 @@
+use vdp::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+// How frames meant for the SegaScope 3-D glasses are presented
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GlassesMode {
+    // Only show frames for one eye, which gives a flicker free 2D picture
+    SingleEye(Eye),
+    // Show every frame as rendered, for use with real shutter glasses
+    #[default]
+    AlternateFrames,
+    // Red/cyan anaglyph, the left eye supplies red and the right green and blue
+    Anaglyph,
+    // Left and right eye frames next to each other in a double width image
+    SideBySide,
+}
+
+// Builds the video output from frames tagged with the eye the glasses had
+// open while they were displayed
+pub struct Glasses {
+    mode: GlassesMode,
+    left: Box<[u32]>,
+    right: Box<[u32]>,
+    output: Box<[u32]>,
+}
+
+impl Glasses {
+    pub fn new(mode: GlassesMode) -> Self {
+        let frame = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice();
+
+        let mut glasses = Glasses {
+            mode,
+            left: frame.clone(),
+            right: frame,
+            output: Box::new([]),
+        };
+        glasses.set_mode(mode);
+
+        glasses
+    }
+
+    pub fn mode(&self) -> GlassesMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: GlassesMode) {
+        self.mode = mode;
+        self.output = vec![0; self.width() * SCREEN_HEIGHT].into_boxed_slice();
+    }
+
+    pub fn width(&self) -> usize {
+        match self.mode {
+            GlassesMode::SideBySide => SCREEN_WIDTH * 2,
+            _ => SCREEN_WIDTH,
+        }
+    }
+
+    pub fn output(&self) -> &[u32] {
+        &self.output
+    }
+
+    // Takes a completed frame, eye is None if the game never touched the
+    // glasses, in which case the frame is shown to both eyes
+    pub fn capture(&mut self, frame: &[u32], eye: Option<Eye>) {
+        match eye {
+            Some(Eye::Left) => self.left.copy_from_slice(frame),
+            Some(Eye::Right) => self.right.copy_from_slice(frame),
+            None => {
+                self.left.copy_from_slice(frame);
+                self.right.copy_from_slice(frame);
+            }
+        }
+
+        match self.mode {
+            GlassesMode::SingleEye(Eye::Left) => self.output.copy_from_slice(&self.left),
+            GlassesMode::SingleEye(Eye::Right) => self.output.copy_from_slice(&self.right),
+            GlassesMode::AlternateFrames => self.output.copy_from_slice(frame),
+            GlassesMode::Anaglyph => {
+                for ((out, &l), &r) in self.output.iter_mut().zip(self.left.iter()).zip(self.right.iter()) {
+                    *out = (l & 0xff0000) | (r & 0x00ffff);
+                }
+            }
+            GlassesMode::SideBySide => {
+                let rows = self.output.chunks_mut(SCREEN_WIDTH * 2)
+                    .zip(self.left.chunks(SCREEN_WIDTH))
+                    .zip(self.right.chunks(SCREEN_WIDTH));
+
+                for ((out, l), r) in rows {
+                    out[..SCREEN_WIDTH].copy_from_slice(l);
+                    out[SCREEN_WIDTH..].copy_from_slice(r);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use super::{Buttons, ControllerPortDevice, Pins, PortInput};
+
+// The standard two button control pad
+#[derive(Default)]
+pub struct Joypad {
+    buttons: Buttons,
+}
+
+impl ControllerPortDevice for Joypad {
+    fn set_input(&mut self, input: &PortInput) {
+        self.buttons = input.buttons;
+    }
+
+    fn read(&self) -> Pins {
+        // Buttons short their pin to ground when pressed
+        Pins::all() - Pins::from_bits_truncate(self.buttons.bits() as u8 & 0x3f)
+    }
+}
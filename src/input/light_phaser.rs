@@ -0,0 +1,93 @@
+use super::{Buttons, ControllerPortDevice, Pins, PortInput, Screen};
+use savestate::{StateReader, StateWriter};
+use vdp::SCREEN_HEIGHT;
+
+// Number of scanlines the sensor sees light for once the beam passes the
+// target, the lens picks up more than a single line
+const SENSOR_LINES: u16 = 8;
+
+// Luminance (0-255) above which the sensor registers a pixel as lit
+const BRIGHTNESS_THRESHOLD: u32 = 0x80;
+
+// The Light Phaser pulls TH low while its sensor sees a bright pixel under
+// the beam, and reports the trigger on TL.
+#[derive(Default)]
+pub struct LightPhaser {
+    target: Option<(u8, u8)>,
+    trigger: bool,
+    sensing: bool,
+}
+
+impl ControllerPortDevice for LightPhaser {
+    fn set_input(&mut self, input: &PortInput) {
+        self.target = input.target;
+        self.trigger = input.buttons.contains(Buttons::BUTTON_1);
+    }
+
+    fn read(&self) -> Pins {
+        let mut pins = Pins::all();
+
+        if self.trigger {
+            pins.remove(Pins::TL);
+        }
+        if self.sensing {
+            pins.remove(Pins::TH);
+        }
+
+        pins
+    }
+
+    // Works out whether the beam passed a lit target since the last tick,
+    // so the latch position doesn't depend on how often the device is ticked
+    fn tick(&mut self, screen: &Screen, cycles: u32) -> Option<u16> {
+        let (x, y) = match self.target {
+            Some((x, y)) => (x as u16, y as u16),
+            None => {
+                self.sensing = false;
+                return None;
+            }
+        };
+
+        let lines = y..(y + SENSOR_LINES).min(SCREEN_HEIGHT as u16);
+        let lit = |line| is_bright(screen.pixel(x as usize, line as usize));
+
+        let now = screen.beam_position();
+        let frame = screen.pixels_per_frame();
+        let elapsed = Screen::cycles_to_pixels(cycles);
+
+        let passed = lines.clone().any(|line| {
+            // Pixels since the beam was last over the target on this line
+            let since = (now + frame - Screen::pixel_position(x, line)) % frame;
+            since < elapsed && lit(line)
+        });
+
+        let line = screen.scanline();
+        self.sensing = lines.contains(&line) && screen.beam_x() >= x && lit(line);
+
+        if passed {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bool(self.sensing);
+
+        w.into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        let mut r = StateReader::new(data);
+        self.sensing = r.bool();
+    }
+}
+
+fn is_bright(rgb: u32) -> bool {
+    let r = (rgb >> 16) & 0xff;
+    let g = (rgb >> 8) & 0xff;
+    let b = rgb & 0xff;
+
+    (r * 299 + g * 587 + b * 114) / 1000 >= BRIGHTNESS_THRESHOLD
+}
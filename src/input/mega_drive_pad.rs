@@ -0,0 +1,126 @@
+use super::{Buttons, ControllerPortDevice, Pins, PortInput, Screen};
+use savestate::{StateReader, StateWriter};
+
+// A six button pad drops back to its first phase if TH stops toggling for
+// about 1.5ms
+const PHASE_TIMEOUT_CYCLES: u32 = 5400;
+
+// A Mega Drive pad used in SMS mode. With TH high it reads as a joypad with B
+// and C as buttons 1 and 2, while TH is driven low it reports A and Start.
+// The six button pad additionally counts TH edges, reporting X, Y, Z and
+// Mode on the fourth high phase.
+pub struct MegaDrivePad {
+    six_button: bool,
+    buttons: Buttons,
+    th: bool,
+    phase: u8,
+    idle_cycles: u32,
+}
+
+impl MegaDrivePad {
+    pub fn three_button() -> Self {
+        MegaDrivePad::new(false)
+    }
+
+    pub fn six_button() -> Self {
+        MegaDrivePad::new(true)
+    }
+
+    fn new(six_button: bool) -> Self {
+        MegaDrivePad {
+            six_button,
+            buttons: Buttons::empty(),
+            th: true,
+            phase: 0,
+            idle_cycles: 0,
+        }
+    }
+
+    fn pressed(&self, buttons: Buttons) -> bool {
+        self.buttons.contains(buttons)
+    }
+}
+
+impl ControllerPortDevice for MegaDrivePad {
+    fn set_input(&mut self, input: &PortInput) {
+        self.buttons = input.buttons;
+    }
+
+    fn write(&mut self, _tr: Option<bool>, th: Option<bool>) {
+        // TH is pulled high when the console isn't driving it
+        let th = th.unwrap_or(true);
+
+        if th != self.th {
+            self.th = th;
+            self.phase = (self.phase + 1) & 7;
+            self.idle_cycles = 0;
+        }
+    }
+
+    fn read(&self) -> Pins {
+        let mut pins = Pins::TH;
+        let six_button_phase = if self.six_button { self.phase } else { 0 };
+
+        if self.th {
+            if six_button_phase == 6 {
+                pins.set(Pins::UP, !self.pressed(Buttons::Z));
+                pins.set(Pins::DOWN, !self.pressed(Buttons::Y));
+                pins.set(Pins::LEFT, !self.pressed(Buttons::X));
+                pins.set(Pins::RIGHT, !self.pressed(Buttons::MODE));
+            } else {
+                pins.set(Pins::UP, !self.pressed(Buttons::UP));
+                pins.set(Pins::DOWN, !self.pressed(Buttons::DOWN));
+                pins.set(Pins::LEFT, !self.pressed(Buttons::LEFT));
+                pins.set(Pins::RIGHT, !self.pressed(Buttons::RIGHT));
+            }
+
+            pins.set(Pins::TL, !self.pressed(Buttons::BUTTON_1));
+            pins.set(Pins::TR, !self.pressed(Buttons::BUTTON_2));
+        } else {
+            match six_button_phase {
+                // All four directions read low to identify a six button pad
+                5 => {}
+                7 => pins.insert(Pins::UP | Pins::DOWN | Pins::LEFT | Pins::RIGHT),
+                _ => {
+                    pins.set(Pins::UP, !self.pressed(Buttons::UP));
+                    pins.set(Pins::DOWN, !self.pressed(Buttons::DOWN));
+                }
+            }
+
+            pins.set(Pins::TL, !self.pressed(Buttons::A));
+            pins.set(Pins::TR, !self.pressed(Buttons::START));
+        }
+
+        pins
+    }
+
+    fn tick(&mut self, _screen: &Screen, cycles: u32) -> Option<u16> {
+        if self.phase != 0 {
+            self.idle_cycles += cycles;
+
+            if self.idle_cycles >= PHASE_TIMEOUT_CYCLES {
+                // Phases are counted in TH edges, so resync to the TH level
+                self.phase = if self.th { 0 } else { 1 };
+                self.idle_cycles = 0;
+            }
+        }
+
+        None
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bool(self.th);
+        w.u8(self.phase);
+        w.u32(self.idle_cycles);
+
+        w.into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        let mut r = StateReader::new(data);
+        self.th = r.bool();
+        self.phase = r.u8();
+        self.idle_cycles = r.u32();
+    }
+}
@@ -0,0 +1,178 @@
+mod joypad;
+mod light_phaser;
+mod mega_drive_pad;
+mod paddle;
+mod sports_pad;
+
+pub use self::joypad::Joypad;
+pub use self::light_phaser::LightPhaser;
+pub use self::mega_drive_pad::MegaDrivePad;
+pub use self::paddle::Paddle;
+pub use self::sports_pad::SportsPad;
+
+use vdp::Vdp;
+
+bitflags! {
+    // Buttons held on a controller, as reported by the frontend
+    #[derive(Default)]
+    pub struct Buttons: u16 {
+        const UP = 0b00000000_00000001;
+        const DOWN = 0b00000000_00000010;
+        const LEFT = 0b00000000_00000100;
+        const RIGHT = 0b00000000_00001000;
+        const BUTTON_1 = 0b00000000_00010000;
+        const BUTTON_2 = 0b00000000_00100000;
+
+        // Extra buttons on a Mega Drive pad, its B and C buttons are
+        // BUTTON_1 and BUTTON_2
+        const A = 0b00000000_01000000;
+        const START = 0b00000000_10000000;
+        const X = 0b00000001_00000000;
+        const Y = 0b00000010_00000000;
+        const Z = 0b00000100_00000000;
+        const MODE = 0b00001000_00000000;
+    }
+}
+
+bitflags! {
+    // Input pin levels of a controller port, a set bit means the pin is high
+    pub struct Pins: u8 {
+        const UP = 0b00000001;
+        const DOWN = 0b00000010;
+        const LEFT = 0b00000100;
+        const RIGHT = 0b00001000;
+        const TL = 0b00010000;
+        const TR = 0b00100000;
+        const TH = 0b01000000;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+}
+
+// The frontend's view of whatever is plugged into a controller port
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PortInput {
+    pub buttons: Buttons,
+    // Screen coordinate a light gun is aimed at, None when it's pointed off screen
+    pub target: Option<(u8, u8)>,
+    // Analog axes. The paddle takes x as its absolute position (0-255), the
+    // Sports Pad takes both as the trackball's motion over the whole frame,
+    // so a movie's one input per frame holds all of it.
+    pub analog: (i16, i16),
+}
+
+// Anything that can be plugged into a controller port. The console sees the
+// device through its seven input pins, and can drive TR and TH when they're
+// configured as outputs through port 0x3F.
+pub trait ControllerPortDevice {
+    // Takes the latest state of the physical controller from the frontend
+    fn set_input(&mut self, _input: &PortInput) {}
+
+    // Levels the console drives TR and TH to, None while the pin is an input
+    fn write(&mut self, _tr: Option<bool>, _th: Option<bool>) {}
+
+    fn read(&self) -> Pins;
+
+    // Advances the device by the given number of T-states, to the current
+    // beam position. Returns the beam x position if the device pulled TH low,
+    // which latches the VDP's H counter.
+    fn tick(&mut self, _screen: &Screen, _cycles: u32) -> Option<u16> {
+        None
+    }
+
+    // Internal state kept in save states, in whatever form the device likes.
+    // The input itself isn't saved, the frontend sets it again before the
+    // next frame.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Restores what save_state returned, which may be cut short or empty if
+    // the state is from an older version
+    fn load_state(&mut self, _data: &[u8]) {}
+}
+
+// What a controller port device can see of the display, the picture drawn so
+// far and where the beam is
+pub struct Screen<'a> {
+    vdp: &'a Vdp,
+}
+
+impl<'a> Screen<'a> {
+    pub(crate) fn new(vdp: &'a Vdp) -> Self {
+        Screen { vdp }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.vdp.pixel(x, y)
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.vdp.scanline()
+    }
+
+    // Horizontal position of the beam in pixels, measured from the left edge
+    // of the active display
+    pub fn beam_x(&self) -> u16 {
+        self.vdp.beam_x()
+    }
+
+    // Position of the beam in pixels since the start of the frame
+    pub fn beam_position(&self) -> u32 {
+        self.vdp.beam_position()
+    }
+
+    pub fn pixels_per_frame(&self) -> u32 {
+        self.vdp.pixels_per_frame()
+    }
+
+    // Pixels the beam covers in the given number of T-states
+    pub fn cycles_to_pixels(cycles: u32) -> u32 {
+        Vdp::cycles_to_pixels(cycles)
+    }
+
+    // Position of a pixel in pixels since the start of the frame
+    pub fn pixel_position(x: u16, line: u16) -> u32 {
+        Vdp::pixel_position(x, line)
+    }
+}
+
+// The devices caduceus knows how to emulate, used where a device has to be
+// named rather than constructed, such as in the game database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Joypad,
+    LightPhaser,
+    Paddle,
+    SportsPad,
+    MegaDrivePad,
+    MegaDrivePad6,
+    Unconnected,
+}
+
+impl DeviceKind {
+    pub fn create(&self) -> Box<dyn ControllerPortDevice> {
+        match *self {
+            DeviceKind::Joypad => Box::new(Joypad::default()),
+            DeviceKind::LightPhaser => Box::new(LightPhaser::default()),
+            DeviceKind::Paddle => Box::new(Paddle::default()),
+            DeviceKind::SportsPad => Box::new(SportsPad::default()),
+            DeviceKind::MegaDrivePad => Box::new(MegaDrivePad::three_button()),
+            DeviceKind::MegaDrivePad6 => Box::new(MegaDrivePad::six_button()),
+            DeviceKind::Unconnected => Box::new(Unconnected),
+        }
+    }
+}
+
+// An empty port, every pin is pulled high
+pub struct Unconnected;
+
+impl ControllerPortDevice for Unconnected {
+    fn read(&self) -> Pins {
+        Pins::all()
+    }
+}
@@ -0,0 +1,81 @@
+use super::{Buttons, ControllerPortDevice, Pins, PortInput, Screen};
+use savestate::{StateReader, StateWriter};
+
+// A Japanese paddle free-runs, swapping between nibbles roughly every 62µs
+const FLIP_CYCLES: u32 = 224;
+
+// The HPD-200 Paddle Control reports its 8-bit position a nibble at a time on
+// the four direction pins, with TR flagging which nibble is on the pins. In
+// export mode the console selects the nibble by driving TH, on a Japanese
+// console TH is left as an input and the paddle flips nibbles by itself.
+#[derive(Default)]
+pub struct Paddle {
+    position: u8,
+    button: bool,
+    th: Option<bool>,
+    high_nibble: bool,
+    cycles: u32,
+}
+
+impl ControllerPortDevice for Paddle {
+    fn set_input(&mut self, input: &PortInput) {
+        self.position = input.analog.0.clamp(0, 0xff) as u8;
+        self.button = input.buttons.contains(Buttons::BUTTON_1);
+    }
+
+    fn write(&mut self, _tr: Option<bool>, th: Option<bool>) {
+        self.th = th;
+
+        if let Some(level) = th {
+            // Driving TH low asks for the low nibble
+            self.high_nibble = level;
+        }
+    }
+
+    fn read(&self) -> Pins {
+        let nibble = if self.high_nibble { self.position >> 4 } else { self.position & 0x0f };
+        let mut pins = Pins::from_bits_truncate(nibble) | Pins::TH;
+
+        pins.set(Pins::TL, !self.button);
+        pins.set(Pins::TR, self.high_nibble);
+
+        pins
+    }
+
+    fn tick(&mut self, _screen: &Screen, cycles: u32) -> Option<u16> {
+        if self.th.is_none() {
+            self.cycles += cycles;
+
+            while self.cycles >= FLIP_CYCLES {
+                self.cycles -= FLIP_CYCLES;
+                self.high_nibble = !self.high_nibble;
+            }
+        }
+
+        None
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u8(match self.th {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        });
+        w.bool(self.high_nibble);
+        w.u32(self.cycles);
+
+        w.into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        let mut r = StateReader::new(data);
+        self.th = match r.u8() {
+            1 => Some(false),
+            2 => Some(true),
+            _ => None,
+        };
+        self.high_nibble = r.bool();
+        self.cycles = r.u32();
+    }
+}
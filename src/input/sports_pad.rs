@@ -0,0 +1,123 @@
+use super::{Buttons, ControllerPortDevice, Pins, PortInput};
+use savestate::{StateReader, StateWriter};
+
+// The Sports Pad is a trackball that reports the motion since it was last
+// read as two signed bytes. Each TH edge moves on to the next nibble, in the
+// order X high, X low, Y high, Y low, and the motion is sampled as the X
+// high nibble is selected. The frontend gives the motion a frame at a time,
+// each set_input replacing whatever the game hasn't read yet, as games read
+// the pad every frame.
+pub struct SportsPad {
+    buttons: Buttons,
+    motion: (i16, i16),
+    latched: (u8, u8),
+    th: bool,
+    phase: u8,
+}
+
+impl Default for SportsPad {
+    fn default() -> Self {
+        SportsPad {
+            buttons: Buttons::empty(),
+            motion: (0, 0),
+            latched: (0, 0),
+            // TH idles high, so the first write low is the first edge
+            th: true,
+            phase: 0,
+        }
+    }
+}
+
+impl ControllerPortDevice for SportsPad {
+    fn set_input(&mut self, input: &PortInput) {
+        self.buttons = input.buttons;
+        self.motion = input.analog;
+    }
+
+    fn write(&mut self, _tr: Option<bool>, th: Option<bool>) {
+        // TH floats high when the console isn't driving it
+        let level = th.unwrap_or(true);
+
+        if level != self.th {
+            self.th = level;
+            self.phase = (self.phase + 1) & 3;
+
+            if self.phase == 1 {
+                self.latched = (clamp(self.motion.0), clamp(self.motion.1));
+                self.motion = (0, 0);
+            }
+        }
+    }
+
+    fn read(&self) -> Pins {
+        let nibble = match self.phase {
+            1 => self.latched.0 >> 4,
+            2 => self.latched.0 & 0x0f,
+            3 => self.latched.1 >> 4,
+            _ => self.latched.1 & 0x0f,
+        };
+        let mut pins = Pins::from_bits_truncate(nibble) | Pins::TH;
+
+        pins.set(Pins::TL, !self.buttons.contains(Buttons::BUTTON_1));
+        pins.set(Pins::TR, !self.buttons.contains(Buttons::BUTTON_2));
+
+        pins
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u16(self.motion.0 as u16);
+        w.u16(self.motion.1 as u16);
+        w.u8(self.latched.0);
+        w.u8(self.latched.1);
+        w.bool(self.th);
+        w.u8(self.phase);
+
+        w.into_bytes()
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        let mut r = StateReader::new(data);
+        self.motion = (r.u16() as i16, r.u16() as i16);
+        self.latched = (r.u8(), r.u8());
+        self.th = r.bool();
+        self.phase = r.u8();
+    }
+}
+
+fn clamp(motion: i16) -> u8 {
+    motion.clamp(-128, 127) as i8 as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads the X and Y motion the way games do, a nibble per TH edge
+    fn read_motion(pad: &mut SportsPad) -> (u8, u8) {
+        let mut nibbles = [0; 4];
+
+        for (i, nibble) in nibbles.iter_mut().enumerate() {
+            pad.write(None, Some(i % 2 == 1));
+            *nibble = pad.read().bits() & 0x0f;
+        }
+
+        (nibbles[0] << 4 | nibbles[1], nibbles[2] << 4 | nibbles[3])
+    }
+
+    #[test]
+    fn motion_is_given_a_frame_at_a_time() {
+        let motion = |x, y| PortInput { analog: (x, y), ..PortInput::default() };
+        let mut pad = SportsPad::default();
+
+        pad.set_input(&motion(5, -3));
+        pad.set_input(&motion(20, -10));
+        assert_eq!(read_motion(&mut pad), (20, -10i8 as u8));
+
+        // Read once, and clamped to a byte
+        assert_eq!(read_motion(&mut pad), (0, 0));
+
+        pad.set_input(&motion(300, -300));
+        assert_eq!(read_motion(&mut pad), (127, -128i8 as u8));
+    }
+}
@@ -0,0 +1,54 @@
+#[macro_use]
+extern crate bitflags;
+extern crate crc32fast;
+extern crate flate2;
+extern crate sha1_smol;
+extern crate zip;
+
+mod bus;
+mod cartridge;
+pub mod cpu;
+mod error;
+mod glasses;
+mod input;
+mod movie;
+mod psg;
+mod region;
+mod regression;
+mod rewind;
+mod savestate;
+mod scheduler;
+mod sms;
+mod symbols;
+mod system;
+#[cfg(test)]
+mod test_support;
+mod trace;
+mod vdp;
+mod vm;
+mod watchpoints;
+
+// The public API. Besides the Z80 core, which can be used on its own through
+// the cpu module, the modules stay private so they can be reorganised without
+// breaking anyone, and everything meant to be used from outside is
+// re-exported here. The bus, VDP, PSG and save state internals aren't, the
+// VM has accessors for what debuggers need of them.
+pub use bus::Slot;
+pub use cartridge::{Cartridge, CartridgeInfo, DatabaseEntry, MapperType, RegionCode};
+pub use cpu::Opcode;
+pub use error::Error;
+pub use glasses::{Eye, GlassesMode};
+pub use input::{Buttons, ControllerPortDevice, DeviceKind, Pins, Port, PortInput, Screen};
+pub use input::{Joypad, LightPhaser, MegaDrivePad, Paddle, SportsPad, Unconnected};
+pub use movie::{Movie, MovieStatus};
+pub use region::{Region, TvStandard};
+pub use regression::{Divergence, FrameHashes, Manifest};
+pub use rewind::Rewind;
+pub use savestate::StateFormat;
+pub use sms::SMS;
+pub use symbols::Symbols;
+pub use system::System;
+pub use trace::{TraceFormat, Tracer};
+pub use vdp::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use vm::{Frame, VM};
+pub use watchpoints::{Access, Hit, Watchpoints};
@@ -1,18 +1,282 @@
-#[macro_use]
-extern crate bitflags;
+extern crate caduceus;
 
-mod bus;
-mod cartridge;
-mod cpu;
-mod sms;
-mod vm;
+mod debugger;
+mod gdb;
 
-use sms::SMS;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::process;
+
+use caduceus::{FrameHashes, Manifest, Movie, Rewind, Symbols, TraceFormat, Tracer, SMS, VM};
+
+use debugger::{Debugger, STATE_SLOTS};
+use gdb::GdbStub;
+
+const USAGE: &str = "\
+Usage: caduceus [--debug | --gdb PORT] [--trace FILE] [--trace-range START-END]...
+                [--trace-bank N]... [--trace-start PC] [--trace-stop PC]
+                [--trace-format full|mame] [--symbols FILE] [--load SLOT]
+                [--rewind MB] [--record FILE | --play FILE] [--frames N]
+                [--hashes FILE] [--golden FILE] [ROM]";
+
+struct Options {
+    rom: String,
+    debug: bool,
+    gdb: Option<u16>,
+    trace: Option<String>,
+    // Filters on what gets traced, see Tracer
+    trace_ranges: Vec<RangeInclusive<u16>>,
+    trace_banks: Vec<usize>,
+    trace_start: Option<u16>,
+    trace_stop: Option<u16>,
+    trace_format: TraceFormat,
+    symbols: Option<String>,
+    // Save state slot to start from
+    load: Option<u8>,
+    // Memory for rewinding, in MB
+    rewind: Option<usize>,
+    // Movie files to record to or play back
+    record: Option<String>,
+    play: Option<String>,
+    // Stop after this many frames
+    frames: Option<u64>,
+    // Manifests of frame hashes to write, and to check against
+    hashes: Option<String>,
+    golden: Option<String>,
+}
 
 fn main() {
+    let options = match parse_args() {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(options) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn parse_args() -> Option<Options> {
+    let mut options = Options {
+        rom: "roms/zexall_sdsc.sms".to_string(),
+        debug: false,
+        gdb: None,
+        trace: None,
+        trace_ranges: Vec::new(),
+        trace_banks: Vec::new(),
+        trace_start: None,
+        trace_stop: None,
+        trace_format: TraceFormat::default(),
+        symbols: None,
+        load: None,
+        rewind: None,
+        record: None,
+        play: None,
+        frames: None,
+        hashes: None,
+        golden: None,
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(args.next()?.parse().ok()?),
+            "--trace" => options.trace = Some(args.next()?),
+            "--trace-range" => options.trace_ranges.push(parse_range(&args.next()?)?),
+            "--trace-bank" => options.trace_banks.push(args.next()?.parse().ok()?),
+            "--trace-start" => options.trace_start = Some(parse_address(&args.next()?)?),
+            "--trace-stop" => options.trace_stop = Some(parse_address(&args.next()?)?),
+            "--trace-format" => options.trace_format = match args.next()?.as_str() {
+                "full" => TraceFormat::Full,
+                "mame" => TraceFormat::Mame,
+                _ => return None,
+            },
+            "--symbols" => options.symbols = Some(args.next()?),
+            "--load" => options.load = Some(args.next()?.parse().ok().filter(|&slot| slot < STATE_SLOTS)?),
+            "--rewind" => options.rewind = Some(args.next()?.parse().ok()?),
+            "--record" => options.record = Some(args.next()?),
+            "--play" => options.play = Some(args.next()?),
+            "--frames" => options.frames = Some(args.next()?.parse().ok()?),
+            "--hashes" => options.hashes = Some(args.next()?),
+            "--golden" => options.golden = Some(args.next()?),
+            _ if arg.starts_with("--") => return None,
+            _ => options.rom = arg,
+        }
+    }
+
+    if options.record.is_some() && options.play.is_some() {
+        return None;
+    }
+
+    // Movies are saved once the run ends, which only happens with --frames
+    // when recording
+    if options.record.is_some() && options.frames.is_none() {
+        return None;
+    }
+
+    // A golden run has to end by itself to be compared
+    if options.golden.is_some() && options.frames.is_none() && options.play.is_none() {
+        return None;
+    }
+
+    let trace_filtered = !options.trace_ranges.is_empty() || !options.trace_banks.is_empty() ||
+        options.trace_start.is_some() || options.trace_stop.is_some() ||
+        options.trace_format != TraceFormat::default();
+
+    if trace_filtered && options.trace.is_none() {
+        return None;
+    }
+
+    Some(options)
+}
+
+// Addresses are in hex, optionally starting with $ or 0x
+fn parse_address(arg: &str) -> Option<u16> {
+    let digits = arg.trim_start_matches('$').trim_start_matches("0x");
+
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_range(arg: &str) -> Option<RangeInclusive<u16>> {
+    let mut parts = arg.splitn(2, '-');
+    let start = parse_address(parts.next()?)?;
+    let end = parse_address(parts.next()?)?;
+
+    if start > end {
+        return None;
+    }
+
+    Some(start..=end)
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
     let mut vm = SMS::default()
-        .with_cartridge(Some("roms/zexall_sdsc.sms"))
-        .build();
+        .with_cartridge(Some(&options.rom))
+        .build()?;
+
+    if let Some(slot) = options.load {
+        let path = debugger::state_path(options.rom.as_ref(), slot);
+        let state = fs::read(&path).map_err(|err| format!("Failed to load {}: {}", path.display(), err))?;
+
+        vm.load_state(&state)?;
+    }
+
+    if let Some(mb) = options.rewind {
+        vm.set_rewind(Some(Rewind::new(mb << 20)));
+    }
+
+    if let Some(ref path) = options.play {
+        let movie = fs::read(path).map_err(|err| format!("Failed to load {}: {}", path, err))?;
+        vm.play_movie(Movie::from_bytes(&movie)?)?;
+    }
+
+    if options.record.is_some() {
+        vm.record_movie();
+    }
+
+    // Without a symbol file given, use one next to the ROM if there is one
+    let symbols = match options.symbols {
+        Some(path) => Symbols::load(path)?,
+        None => Symbols::find_for_rom(&options.rom).unwrap_or_default(),
+    };
+
+    if let Some(path) = options.trace {
+        let symbols = if symbols.is_empty() { None } else { Some(symbols.clone()) };
+        let mut tracer = Tracer::to_file(&path)?
+            .with_format(options.trace_format)
+            .with_start(options.trace_start)
+            .with_stop(options.trace_stop)
+            .with_symbols(symbols);
+
+        for range in options.trace_ranges {
+            tracer = tracer.with_pc_range(range);
+        }
+        for bank in options.trace_banks {
+            tracer = tracer.with_bank(bank);
+        }
+
+        vm.set_tracer(Some(tracer));
+    }
+
+    if let Some(port) = options.gdb {
+        GdbStub::new(vm).serve(port)?;
+        return Ok(());
+    }
+
+    if options.debug {
+        Debugger::new(vm, symbols, &options.rom).run();
+        return Ok(());
+    }
+
+    // Read up front so a bad manifest doesn't wait for the whole run
+    let golden = match options.golden {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).map_err(|err| format!("Failed to load {}: {}", path, err))?;
+            Some(Manifest::parse(&text)?)
+        }
+        None => None,
+    };
+
+    // Only kept when it's needed, an open ended run would grow it forever
+    let mut manifest = if options.hashes.is_some() || golden.is_some() { Some(Manifest::new()) } else { None };
+    let mut frames = 0;
+
+    while options.frames.is_none_or(|limit| frames < limit) {
+        if vm.movie_status().is_some_and(|status| status.finished()) {
+            break;
+        }
+
+        let frame = vm.run_frame()?;
+        if let Some(ref mut manifest) = manifest {
+            manifest.push(FrameHashes::of(&frame));
+        }
+
+        print_console(&mut vm);
+
+        frames += 1;
+    }
+
+    let manifest = manifest.unwrap_or_default();
+
+    if let Some(path) = options.hashes {
+        fs::write(&path, manifest.to_string()).map_err(|err| format!("Failed to save {}: {}", path, err))?;
+    }
+
+    if let Some(golden) = golden {
+        if let Some(divergence) = golden.compare(&manifest) {
+            return Err(format!("Run diverged from the golden manifest: {}", divergence).into());
+        }
+
+        println!("All {} frames match the golden manifest", manifest.len());
+    }
+
+    if let Some(status) = vm.movie_status() {
+        if let Some(frame) = status.desync {
+            return Err(format!("Movie desynced at frame {}", frame).into());
+        }
+    }
+
+    if let (Some(path), Some(movie)) = (options.record, vm.stop_movie()) {
+        fs::write(&path, movie.to_bytes()).map_err(|err| format!("Failed to save {}: {}", path, err))?;
+    }
+
+    Ok(())
+}
 
-    vm.run();
+// Passes on anything the game wrote to the SDSC debug console
+fn print_console(vm: &mut VM) {
+    if !vm.console().is_empty() {
+        let _ = io::stdout().write_all(vm.console());
+        let _ = io::stdout().flush();
+        vm.clear_console();
+    }
 }
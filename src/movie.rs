@@ -0,0 +1,351 @@
+use error::Error;
+use input::{Buttons, PortInput};
+use region::{Region, TvStandard};
+use savestate::{StateReader, StateWriter};
+use system::System;
+
+// Movie files use the same chunks as save states, after their own header:
+//
+//   "CADMOVIE" u16 version
+//   META  system, region, TV standard, and the SHA-1 of the media in each slot
+//   STAT  the save state the movie starts from, left out when it starts at
+//         power on
+//   INPT  the input to both controller ports for every frame
+//   SYNC  checksums of the machine state at regular frames
+const MAGIC: &[u8; 8] = b"CADMOVIE";
+const VERSION: u16 = 1;
+
+// Frames between the checksums used to spot a desync
+const SYNC_INTERVAL: usize = 60;
+
+// Everything needed to replay a run of the machine: where it started, the
+// settings and media it ran with, and the input it was given each frame
+#[derive(Debug, Clone)]
+pub struct Movie {
+    pub system: System,
+    pub region: Region,
+    pub tv_standard: TvStandard,
+    // SHA-1 of the media in the BIOS, cartridge, card and expansion slots
+    pub media: [Option<[u8; 20]>; 4],
+    // The save state the movie starts from, None to start at power on
+    pub start: Option<Vec<u8>>,
+    pub frames: Vec<[PortInput; 2]>,
+    // CRC32 of the machine state at the end of some frames, by frame number
+    sync: Vec<(usize, u32)>,
+}
+
+// How far through recording or playing back a movie the VM is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovieStatus {
+    pub recording: bool,
+    // The next frame to record or play
+    pub frame: usize,
+    pub frames: usize,
+    // The first frame the machine ended up in a different state than when
+    // the movie was recorded
+    pub desync: Option<usize>,
+}
+
+impl MovieStatus {
+    // Whether playback has run out of frames
+    pub fn finished(&self) -> bool {
+        !self.recording && self.frame >= self.frames
+    }
+}
+
+impl Movie {
+    pub fn new(system: System, region: Region, tv_standard: TvStandard, media: [Option<[u8; 20]>; 4],
+               start: Option<Vec<u8>>) -> Self {
+        Movie {
+            system,
+            region,
+            tv_standard,
+            media,
+            start,
+            frames: Vec::new(),
+            sync: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Adds a frame's input, along with a checksum of the state the machine
+    // was left in every so often
+    pub fn record(&mut self, input: [PortInput; 2], state: impl FnOnce() -> Vec<u8>) {
+        let frame = self.frames.len();
+        self.frames.push(input);
+
+        if frame.is_multiple_of(SYNC_INTERVAL) {
+            self.sync.push((frame, crc32fast::hash(&state())));
+        }
+    }
+
+    // Checks the state at the end of a frame against the recording, true if
+    // it matches or there's no checksum for that frame
+    pub fn check(&self, frame: usize, state: impl FnOnce() -> Vec<u8>) -> bool {
+        match self.sync.binary_search_by_key(&frame, |&(frame, _)| frame) {
+            Ok(i) => crc32fast::hash(&state()) == self.sync[i].1,
+            Err(_) => true,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.raw(MAGIC);
+        w.u16(VERSION);
+
+        w.chunk(b"META", |w| {
+            w.u8(match self.system {
+                System::Sms => 0,
+                System::GameGear => 1,
+                System::Sg1000 => 2,
+                System::Sc3000 => 3,
+            });
+            w.u8(match self.region {
+                Region::Japan => 0,
+                Region::Export => 1,
+            });
+            w.u8(match self.tv_standard {
+                TvStandard::Ntsc => 0,
+                TvStandard::Pal => 1,
+            });
+
+            for media in &self.media {
+                w.bool(media.is_some());
+                w.raw(&media.unwrap_or_default());
+            }
+        });
+
+        if let Some(ref start) = self.start {
+            w.chunk(b"STAT", |w| w.bytes(start));
+        }
+
+        w.chunk(b"INPT", |w| {
+            w.u32(self.frames.len() as u32);
+
+            for ports in &self.frames {
+                for input in ports {
+                    let target = input.target.unwrap_or_default();
+
+                    w.u16(input.buttons.bits());
+                    w.bool(input.target.is_some());
+                    w.u8(target.0);
+                    w.u8(target.1);
+                    w.u16(input.analog.0 as u16);
+                    w.u16(input.analog.1 as u16);
+                }
+            }
+        });
+
+        w.chunk(b"SYNC", |w| {
+            w.u32(self.sync.len() as u32);
+
+            for &(frame, crc) in &self.sync {
+                w.u32(frame as u32);
+                w.u32(crc);
+            }
+        });
+
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, Error> {
+        Movie::parse(data).map_err(|reason| Error::InvalidMovie { reason })
+    }
+
+    fn parse(data: &[u8]) -> Result<Movie, &'static str> {
+        let mut r = StateReader::new(data);
+
+        if r.raw(MAGIC.len()) != MAGIC {
+            return Err("not a caduceus movie");
+        }
+        if r.u16() != VERSION {
+            return Err("unsupported version");
+        }
+
+        let mut movie = Movie::new(System::default(), Region::default(), TvStandard::default(), [None; 4], None);
+        let mut meta = false;
+
+        while let Some((tag, mut chunk)) = r.chunk() {
+            match &tag {
+                b"META" => {
+                    meta = true;
+                    movie.system = match chunk.u8() {
+                        0 => System::Sms,
+                        1 => System::GameGear,
+                        2 => System::Sg1000,
+                        3 => System::Sc3000,
+                        _ => return Err("unknown system"),
+                    };
+                    movie.region = if chunk.u8() == 0 { Region::Japan } else { Region::Export };
+                    movie.tv_standard = if chunk.u8() == 0 { TvStandard::Ntsc } else { TvStandard::Pal };
+
+                    for media in movie.media.iter_mut() {
+                        let present = chunk.bool();
+                        let mut sha1 = [0; 20];
+                        let bytes = chunk.raw(20);
+                        sha1[..bytes.len()].copy_from_slice(bytes);
+
+                        *media = if present { Some(sha1) } else { None };
+                    }
+                }
+                b"STAT" => movie.start = Some(chunk.bytes().to_vec()),
+                b"INPT" => {
+                    let frames = chunk.u32() as usize;
+
+                    for _ in 0..frames {
+                        if chunk.is_empty() {
+                            return Err("truncated");
+                        }
+
+                        let mut ports = [PortInput::default(); 2];
+
+                        for input in ports.iter_mut() {
+                            input.buttons = Buttons::from_bits_truncate(chunk.u16());
+                            let has_target = chunk.bool();
+                            let target = (chunk.u8(), chunk.u8());
+                            input.target = if has_target { Some(target) } else { None };
+                            input.analog = (chunk.u16() as i16, chunk.u16() as i16);
+                        }
+
+                        movie.frames.push(ports);
+                    }
+                }
+                b"SYNC" => {
+                    for _ in 0..chunk.u32() {
+                        if chunk.is_empty() {
+                            return Err("truncated");
+                        }
+
+                        movie.sync.push((chunk.u32() as usize, chunk.u32()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !r.is_empty() {
+            return Err("truncated");
+        }
+        if !meta {
+            return Err("no settings");
+        }
+
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input::Port;
+    use test_support::TestMachine;
+
+    fn input(frame: usize) -> PortInput {
+        PortInput {
+            buttons: Buttons::from_bits_truncate(frame as u16),
+            target: if frame.is_multiple_of(2) { Some((frame as u8, 255 - frame as u8)) } else { None },
+            analog: (-(frame as i16), frame as i16 * 100),
+        }
+    }
+
+    // A movie of the test machine given different input every frame
+    fn record(frames: usize) -> Movie {
+        let mut vm = TestMachine::default().build();
+        vm.record_movie();
+
+        for frame in 0..frames {
+            vm.set_input(Port::A, &input(frame));
+            vm.set_input(Port::B, &input(frame + 1));
+            vm.run_frame().unwrap();
+        }
+
+        vm.stop_movie().unwrap()
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let movie = record(130);
+        let parsed = Movie::from_bytes(&movie.to_bytes()).unwrap();
+
+        assert_eq!(parsed.system, movie.system);
+        assert_eq!(parsed.region, movie.region);
+        assert_eq!(parsed.tv_standard, movie.tv_standard);
+        assert_eq!(parsed.media, movie.media);
+        assert_eq!(parsed.start, movie.start);
+        assert_eq!(parsed.sync, movie.sync);
+        assert_eq!(parsed.len(), 130);
+
+        for (frame, ports) in parsed.frames.iter().enumerate() {
+            for (i, parsed) in ports.iter().enumerate() {
+                let recorded = input(frame + i);
+                assert_eq!(parsed.buttons, recorded.buttons);
+                assert_eq!(parsed.target, recorded.target);
+                assert_eq!(parsed.analog, recorded.analog);
+            }
+        }
+    }
+
+    #[test]
+    fn playback_stays_in_sync() {
+        let movie = record(130);
+        assert_eq!(movie.sync.len(), 3);
+
+        let mut vm = TestMachine::default().build();
+        vm.play_movie(movie).unwrap();
+
+        while !vm.movie_status().unwrap().finished() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.movie_status().unwrap().desync, None);
+    }
+
+    #[test]
+    fn input_is_taken_again_once_playback_finishes() {
+        let mut vm = TestMachine::default().build();
+        vm.play_movie(record(2)).unwrap();
+
+        // Ignored, the movie's input is used
+        vm.set_input(Port::A, &input(50));
+        vm.run_frame().unwrap();
+        vm.run_frame().unwrap();
+        assert!(vm.movie_status().unwrap().finished());
+
+        vm.set_input(Port::B, &input(99));
+        vm.record_movie();
+        vm.run_frame().unwrap();
+
+        let frames = vm.stop_movie().unwrap().frames;
+        assert_eq!(frames[0][0].buttons, input(1).buttons);
+        assert_eq!(frames[0][1].buttons, input(99).buttons);
+    }
+
+    #[test]
+    fn sync_checks_the_state() {
+        let movie = record(61);
+
+        assert!(!movie.check(60, Vec::new));
+        // Frames without a checksum always pass
+        assert!(movie.check(59, Vec::new));
+    }
+
+    #[test]
+    fn bad_movies_are_rejected() {
+        let bytes = record(2).to_bytes();
+
+        assert!(Movie::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert_eq!(Movie::parse(b"CADUCEUS\x01\x00").err(), Some("not a caduceus movie"));
+
+        // The settings are required
+        let mut w = StateWriter::new();
+        w.u64(u64::from_le_bytes(*MAGIC));
+        w.u16(VERSION);
+        assert_eq!(Movie::parse(&w.into_bytes()).err(), Some("no settings"));
+    }
+}
@@ -0,0 +1,201 @@
+use savestate::{StateReader, StateWriter};
+
+// The PSG counts down once every 16 CPU cycles
+const CLOCK_DIVIDER: u32 = 16;
+
+// Output level of a channel at each attenuation, 2dB per step with 15 silent
+const VOLUMES: [i16; 16] = [
+    8191, 6507, 5168, 4105, 3261, 2590, 2057, 1642,
+    1298, 1031, 819, 650, 516, 410, 326, 0,
+];
+
+// Noise shift register as reset by a write to the noise register
+const NOISE_RESET: u16 = 0x8000;
+
+// The SN76489 sound chip, three square wave tone channels and a noise channel
+pub struct Psg {
+    // Tone periods for channels 0-2, and the noise control for channel 3
+    tones: [u16; 4],
+    volumes: [u8; 4],
+    counters: [u16; 4],
+    outputs: [bool; 4],
+    noise: u16,
+    // Register selected by the last latch byte
+    latched: usize,
+    latched_volume: bool,
+
+    cpu_clock: u32,
+    sample_rate: u32,
+    // CPU cycles not yet run through a PSG clock
+    cycles: u32,
+    // Tracks how far through the current output sample we are, in units of
+    // sample_rate per PSG clock
+    sample_phase: u32,
+    sample_sum: i32,
+    sample_count: i32,
+    samples: Vec<i16>,
+}
+
+impl Psg {
+    pub fn new(cpu_clock: u32, sample_rate: u32) -> Self {
+        Psg {
+            tones: [0; 4],
+            volumes: [0x0f; 4],
+            counters: [0; 4],
+            outputs: [true; 4],
+            noise: NOISE_RESET,
+            latched: 0,
+            latched_volume: false,
+
+            cpu_clock,
+            sample_rate,
+            cycles: 0,
+            sample_phase: 0,
+            sample_sum: 0,
+            sample_count: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    // Samples waiting to be collected aren't saved
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for channel in 0..4 {
+            w.u16(self.tones[channel]);
+            w.u8(self.volumes[channel]);
+            w.u16(self.counters[channel]);
+            w.bool(self.outputs[channel]);
+        }
+
+        w.u16(self.noise);
+        w.u8(self.latched as u8);
+        w.bool(self.latched_volume);
+        w.u32(self.cycles);
+        w.u32(self.sample_phase);
+        w.u32(self.sample_sum as u32);
+        w.u32(self.sample_count as u32);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        for channel in 0..4 {
+            self.tones[channel] = r.u16() & 0x3ff;
+            self.volumes[channel] = r.u8() & 0x0f;
+            self.counters[channel] = r.u16();
+            self.outputs[channel] = r.bool();
+        }
+
+        self.noise = r.u16();
+        self.latched = (r.u8() & 0x03) as usize;
+        self.latched_volume = r.bool();
+        self.cycles = r.u32();
+        self.sample_phase = r.u32();
+        self.sample_sum = r.u32() as i32;
+        self.sample_count = r.u32() as i32;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Mono samples generated since they were last cleared
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn write(&mut self, val: u8) {
+        if val & 0x80 != 0 {
+            self.latched = ((val >> 5) & 0x03) as usize;
+            self.latched_volume = val & 0x10 != 0;
+        }
+
+        let channel = self.latched;
+
+        if self.latched_volume {
+            self.volumes[channel] = val & 0x0f;
+        } else if channel == 3 {
+            self.tones[3] = (val & 0x07) as u16;
+            self.noise = NOISE_RESET;
+        } else if val & 0x80 != 0 {
+            self.tones[channel] = (self.tones[channel] & 0x3f0) | (val & 0x0f) as u16;
+        } else {
+            self.tones[channel] = (self.tones[channel] & 0x00f) | (((val & 0x3f) as u16) << 4);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+
+        while self.cycles >= CLOCK_DIVIDER {
+            self.cycles -= CLOCK_DIVIDER;
+            self.clock();
+
+            // Average the output over each sample period
+            self.sample_sum += self.output() as i32;
+            self.sample_count += 1;
+
+            self.sample_phase += self.sample_rate;
+            if self.sample_phase >= self.cpu_clock / CLOCK_DIVIDER {
+                self.sample_phase -= self.cpu_clock / CLOCK_DIVIDER;
+                self.samples.push((self.sample_sum / self.sample_count) as i16);
+                self.sample_sum = 0;
+                self.sample_count = 0;
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        for channel in 0..3 {
+            if self.counters[channel] > 0 {
+                self.counters[channel] -= 1;
+            }
+
+            if self.counters[channel] == 0 {
+                self.counters[channel] = self.tones[channel];
+                // Periods of 0 and 1 hold the output high, which games use
+                // to play samples by writing volumes
+                self.outputs[channel] = self.tones[channel] <= 1 || !self.outputs[channel];
+            }
+        }
+
+        if self.counters[3] > 0 {
+            self.counters[3] -= 1;
+        }
+
+        if self.counters[3] == 0 {
+            self.counters[3] = match self.tones[3] & 0x03 {
+                0 => 0x10,
+                1 => 0x20,
+                2 => 0x40,
+                _ => self.tones[2].max(1),
+            };
+
+            self.outputs[3] = !self.outputs[3];
+
+            // The shift register moves on each rising edge
+            if self.outputs[3] {
+                let feedback = if self.tones[3] & 0x04 != 0 {
+                    // White noise taps bits 0 and 3
+                    (self.noise & 0x0009).count_ones() as u16 & 1
+                } else {
+                    self.noise & 1
+                };
+
+                self.noise = (self.noise >> 1) | (feedback << 15);
+            }
+        }
+    }
+
+    fn output(&self) -> i16 {
+        let tones = (0..3)
+            .filter(|&channel| self.outputs[channel])
+            .map(|channel| VOLUMES[self.volumes[channel] as usize])
+            .sum::<i16>();
+
+        let noise = if self.noise & 1 != 0 { VOLUMES[self.volumes[3] as usize] } else { 0 };
+
+        tones + noise
+    }
+}
@@ -0,0 +1,48 @@
+// Which market a console was sold in, Japanese consoles differ in how the I/O
+// port control register reads back
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Japan,
+    #[default]
+    Export,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TvStandard {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl TvStandard {
+    // CPU clock in Hz, derived from the colour subcarrier of each standard
+    pub fn cpu_clock(&self) -> u32 {
+        match *self {
+            TvStandard::Ntsc => 3_579_545,
+            TvStandard::Pal => 3_546_893,
+        }
+    }
+
+    pub fn lines_per_frame(&self) -> u16 {
+        match *self {
+            TvStandard::Ntsc => 262,
+            TvStandard::Pal => 313,
+        }
+    }
+
+    // Maps a scanline to the value read from the V counter in 192 line mode.
+    // The counter jumps back partway through the vertical blank so it fits
+    // in eight bits.
+    pub fn v_counter(&self, line: u16) -> u8 {
+        let (last, jump_to) = match *self {
+            TvStandard::Ntsc => (0xda, 0xd5),
+            TvStandard::Pal => (0xf2, 0xba),
+        };
+
+        if line <= last {
+            line as u8
+        } else {
+            (line - (last + 1) + jump_to) as u8
+        }
+    }
+}
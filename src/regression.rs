@@ -0,0 +1,208 @@
+use std::fmt;
+
+use error::Error;
+use vm::Frame;
+
+// CRC32s of a frame's video and audio output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHashes {
+    pub video: u32,
+    pub audio: u32,
+}
+
+impl FrameHashes {
+    pub fn of(frame: &Frame) -> Self {
+        let mut video = crc32fast::Hasher::new();
+        for pixel in frame.video {
+            video.update(&pixel.to_le_bytes());
+        }
+
+        let mut audio = crc32fast::Hasher::new();
+        for sample in frame.audio {
+            audio.update(&sample.to_le_bytes());
+        }
+
+        FrameHashes {
+            video: video.finalize(),
+            audio: audio.finalize(),
+        }
+    }
+}
+
+// The first frame two runs differ at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub frame: usize,
+    pub video: bool,
+    pub audio: bool,
+    // One run ended before the other got to this frame
+    pub missing: bool,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match (self.missing, self.video, self.audio) {
+            (true, _, _) => "is missing from one run",
+            (_, true, true) => "has different video and audio",
+            (_, true, false) => "has different video",
+            _ => "has different audio",
+        };
+
+        write!(f, "Frame {} {}", self.frame, what)
+    }
+}
+
+// The hashes of every frame of a run, kept as a golden manifest to check
+// later runs against. Manifests are text, a line per frame with the frame
+// number and its video and audio hashes in hex. Blank lines and lines
+// starting with # are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    frames: Vec<FrameHashes>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest::default()
+    }
+
+    pub fn push(&mut self, hashes: FrameHashes) {
+        self.frames.push(hashes);
+    }
+
+    pub fn frames(&self) -> &[FrameHashes] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<Manifest, Error> {
+        let mut manifest = Manifest::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let hashes = match fields[..] {
+                [frame, video, audio] if frame.parse() == Ok(manifest.len()) => {
+                    match (u32::from_str_radix(video, 16), u32::from_str_radix(audio, 16)) {
+                        (Ok(video), Ok(audio)) => Some(FrameHashes { video, audio }),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+
+            match hashes {
+                Some(hashes) => manifest.push(hashes),
+                None => return Err(Error::InvalidManifest { line: i + 1 }),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    // Compares a run against this one, None if every frame matches
+    pub fn compare(&self, run: &Manifest) -> Option<Divergence> {
+        let differs = self.frames.iter().zip(&run.frames).position(|(a, b)| a != b);
+
+        match differs {
+            Some(frame) => Some(Divergence {
+                frame,
+                video: self.frames[frame].video != run.frames[frame].video,
+                audio: self.frames[frame].audio != run.frames[frame].audio,
+                missing: false,
+            }),
+            None if self.len() != run.len() => Some(Divergence {
+                frame: self.len().min(run.len()),
+                video: false,
+                audio: false,
+                missing: true,
+            }),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# frame video audio")?;
+
+        for (i, hashes) in self.frames.iter().enumerate() {
+            writeln!(f, "{} {:08x} {:08x}", i, hashes.video, hashes.audio)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TestMachine;
+
+    // The hashes of the test machine's first frames, with one changed
+    fn run(frames: usize, change: Option<(usize, FrameHashes)>) -> Manifest {
+        let mut vm = TestMachine::default().build();
+        let mut manifest = Manifest::new();
+
+        for frame in 0..frames {
+            let hashes = FrameHashes::of(&vm.run_frame().unwrap());
+
+            manifest.push(match change {
+                Some((changed, other)) if changed == frame => other,
+                _ => hashes,
+            });
+        }
+
+        manifest
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let manifest = run(5, None);
+
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let manifest = Manifest::parse("# frame video audio\n\n0 0000000a 0000000b\n  # note\n1 c d\n").unwrap();
+
+        assert_eq!(manifest.frames(), &[FrameHashes { video: 10, audio: 11 }, FrameHashes { video: 12, audio: 13 }]);
+    }
+
+    #[test]
+    fn parse_reports_the_bad_line() {
+        for &(text, bad) in &[("0 1 2\n2 3 4\n", 2), ("0 1\n", 1), ("# header\n0 1 xyz\n", 2)] {
+            match Manifest::parse(text) {
+                Err(Error::InvalidManifest { line }) => assert_eq!(line, bad),
+                other => panic!("expected an invalid manifest, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn compare_finds_the_first_difference() {
+        let golden = run(3, None);
+        let frame = golden.frames()[1];
+
+        assert_eq!(golden.compare(&run(3, None)), None);
+        assert_eq!(golden.compare(&run(3, Some((1, FrameHashes { audio: !frame.audio, ..frame })))),
+                   Some(Divergence { frame: 1, video: false, audio: true, missing: false }));
+        assert_eq!(golden.compare(&run(2, None)),
+                   Some(Divergence { frame: 2, video: false, audio: false, missing: true }));
+        assert_eq!(run(1, None).compare(&golden),
+                   Some(Divergence { frame: 1, video: false, audio: false, missing: true }));
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+
+use input::PortInput;
+
+// Keyframes are taken this often by default, about once a second
+const KEYFRAME_INTERVAL: usize = 60;
+
+// A run of snapshots stored as differences from the keyframe that starts it
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+    // The input each snapshot's frame ran with, the keyframe's first
+    inputs: Vec<[PortInput; 2]>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+// Save states from the last frames run, so they can be stepped back through.
+// Every snapshot besides the keyframes is stored as its XOR with the last
+// keyframe, run length encoded, which is small since little of the machine
+// changes from frame to frame. Once the snapshots go over the memory budget
+// the oldest keyframe and the deltas that depend on it are dropped.
+pub struct Rewind {
+    budget: usize,
+    keyframe_interval: usize,
+    groups: VecDeque<Group>,
+    size: usize,
+}
+
+impl Rewind {
+    // Keeps up to budget bytes of snapshots
+    pub fn new(budget: usize) -> Self {
+        Rewind {
+            budget,
+            keyframe_interval: KEYFRAME_INTERVAL,
+            groups: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    // Takes a keyframe every interval snapshots. More often uses more
+    // memory, less often makes each delta bigger as the machine drifts
+    // further from its keyframe.
+    pub fn with_keyframe_interval(mut self, interval: usize) -> Self {
+        self.keyframe_interval = interval.max(1);
+        self
+    }
+
+    // Number of snapshots held
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| group.deltas.len() + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    // Memory used by the snapshots, in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.size = 0;
+    }
+
+    // Adds the state at the end of a frame, along with the input the frame
+    // ran with so it can be run again
+    pub fn push(&mut self, state: &[u8], inputs: [PortInput; 2]) {
+        let keyframe = match self.groups.back_mut() {
+            Some(group) if group.deltas.len() + 1 < self.keyframe_interval => {
+                let delta = encode(&group.keyframe, state);
+                self.size += delta.len();
+                group.deltas.push(delta);
+                group.inputs.push(inputs);
+                false
+            }
+            _ => true,
+        };
+
+        if keyframe {
+            self.size += state.len();
+            self.groups.push_back(Group { keyframe: state.to_vec(), deltas: Vec::new(), inputs: vec![inputs] });
+        }
+
+        // The newest group always stays, even if it's over budget alone
+        while self.size > self.budget && self.groups.len() > 1 {
+            if let Some(group) = self.groups.pop_front() {
+                self.size -= group.size();
+            }
+        }
+    }
+
+    // Removes and returns the newest snapshot and its frame's input
+    pub fn pop(&mut self) -> Option<(Vec<u8>, [PortInput; 2])> {
+        let group = self.groups.back_mut()?;
+        let inputs = group.inputs.pop()?;
+
+        match group.deltas.pop() {
+            Some(delta) => {
+                self.size -= delta.len();
+                Some((decode(&group.keyframe, &delta), inputs))
+            }
+            None => {
+                let group = self.groups.pop_back()?;
+                self.size -= group.keyframe.len();
+                Some((group.keyframe, inputs))
+            }
+        }
+    }
+
+    // The newest snapshot, left in place
+    pub fn last(&self) -> Option<Vec<u8>> {
+        let group = self.groups.back()?;
+
+        match group.deltas.last() {
+            Some(delta) => Some(decode(&group.keyframe, delta)),
+            None => Some(group.keyframe.clone()),
+        }
+    }
+}
+
+// A delta is the length of the state, then the state XORed with the
+// keyframe as runs: a count of zero bytes, then a count of literal bytes
+// and the bytes themselves. Counts are LEB128 varints.
+fn encode(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, state.len());
+
+    let xor = |i: usize| state[i] ^ keyframe.get(i).cloned().unwrap_or(0);
+    let mut i = 0;
+
+    while i < state.len() {
+        let zeros_start = i;
+        while i < state.len() && xor(i) == 0 {
+            i += 1;
+        }
+
+        // A lone zero between changed bytes is cheaper kept as a literal
+        let literal_start = i;
+        while i < state.len() && (xor(i) != 0 || (i + 1 < state.len() && xor(i + 1) != 0)) {
+            i += 1;
+        }
+
+        write_varint(&mut delta, literal_start - zeros_start);
+        write_varint(&mut delta, i - literal_start);
+        delta.extend((literal_start..i).map(xor));
+    }
+
+    delta
+}
+
+fn decode(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+
+    let mut state = keyframe.to_vec();
+    state.resize(len, 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+
+        for &byte in &delta[pos..pos + literals] {
+            state[i] ^= byte;
+            i += 1;
+        }
+        pos += literals;
+    }
+
+    state
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        buf.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+
+        val |= (byte as usize & 0x7f) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return val;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TestMachine;
+    use watchpoints::Access;
+
+    fn inputs(frame: usize) -> [PortInput; 2] {
+        let mut inputs = [PortInput::default(); 2];
+        inputs[0].analog = (frame as i16, 0);
+
+        inputs
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let states = TestMachine::default().snapshots(2);
+        let keyframe = &states[0];
+
+        for state in [states[1].clone(), keyframe.clone(), vec![1, 2, 3], states[1][..100].to_vec(), Vec::new()].iter() {
+            let delta = encode(keyframe, state);
+            assert_eq!(&decode(keyframe, &delta), state);
+        }
+
+        // Little changes from one frame to the next, so deltas are small
+        assert!(encode(keyframe, &states[1]).len() < 250);
+    }
+
+    #[test]
+    fn pops_newest_first() {
+        let states = TestMachine::default().snapshots(7);
+        let mut rewind = Rewind::new(1 << 20).with_keyframe_interval(3);
+
+        for (frame, state) in states.iter().enumerate() {
+            rewind.push(state, inputs(frame));
+        }
+        assert_eq!(rewind.len(), 7);
+        assert_eq!(rewind.last().as_ref(), states.last());
+
+        for (frame, state) in states.iter().enumerate().rev() {
+            let (popped, popped_inputs) = rewind.pop().unwrap();
+            assert_eq!(&popped, state);
+            assert_eq!(popped_inputs[0].analog, inputs(frame)[0].analog);
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.size(), 0);
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn oldest_groups_are_dropped_over_budget() {
+        let states = TestMachine::default().snapshots(9);
+
+        // Room for two groups of a keyframe and two deltas, but not three
+        let budget = 2 * states[0].len() + 1000;
+        let mut rewind = Rewind::new(budget).with_keyframe_interval(3);
+
+        for (frame, state) in states.iter().enumerate() {
+            rewind.push(state, inputs(frame));
+            assert!(rewind.size() <= budget);
+        }
+
+        assert_eq!(rewind.len(), 6);
+        for state in states[3..].iter().rev() {
+            assert_eq!(&rewind.pop().unwrap().0, state);
+        }
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn newest_group_stays_over_budget() {
+        let states = TestMachine::default().snapshots(1);
+        let mut rewind = Rewind::new(10);
+        rewind.push(&states[0], inputs(0));
+
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.pop().unwrap().0, states[0]);
+    }
+
+    #[test]
+    fn rewind_frame_runs_the_frame_before_again() {
+        let states = TestMachine::default().snapshots(5);
+        let mut vm = TestMachine::default().build();
+        vm.set_rewind(Some(Rewind::new(1 << 20)));
+
+        for _ in 0..5 {
+            vm.run_frame().unwrap();
+        }
+
+        // The re-run writes to the watched address, but it's been seen before
+        vm.watchpoints_mut().add(Access::Write, 0xc000);
+
+        assert!(vm.rewind_frame().unwrap());
+        assert_eq!(vm.save_state(), states[3]);
+        assert_eq!(vm.rewind().unwrap().len(), 4);
+        assert!(vm.watchpoints_mut().take_hit().is_none());
+
+        assert!(vm.rewind_frame().unwrap());
+        assert!(vm.rewind_frame().unwrap());
+        assert_eq!(vm.save_state(), states[1]);
+        assert!(!vm.rewind_frame().unwrap());
+    }
+}
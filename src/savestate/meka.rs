@@ -0,0 +1,420 @@
+// Meka's save states, .S00 to .S09. They're Meka's internal structures
+// written out as its 32-bit builds lay them out in memory:
+//
+//   0x00  "MEKA" 0x1A, version, driver (0 for the Master System)
+//   0x07  ROM CRC32
+//   0x0B  registers of Marat Fayzullin's Z80 core, 60 bytes
+//   0x47  VDP and machine state, 40 bytes
+//   0x6F  mapper registers 0xFFFC-0xFFFF
+//   0x73  RAM 8KB, VRAM 16KB, CRAM 32 bytes, PSG 44 bytes, "EOF"
+//
+// Converting goes through caduceus's own format. Meka doesn't keep the beam
+// position or the CPU's cycle count the same way, so an imported state
+// picks up at the top of a frame, and whatever else caduceus keeps that Meka
+// doesn't is reset. Cartridge RAM is kept in Meka's battery backup files
+// rather than its states, so it's left alone.
+
+use super::{chunks, StateReader, StateWriter};
+
+pub const MAGIC: &[u8; 5] = b"MEKA\x1a";
+const VERSION: u8 = 0x0e;
+const DRIVER_SMS: u8 = 0;
+
+const Z80_SIZE: usize = 60;
+const MACHINE_SIZE: usize = 40;
+const PSG_SIZE: usize = 44;
+
+// Bits of the Z80 core's IFF register
+const IFF_1: u8 = 0x01;
+const IFF_IM1: u8 = 0x02;
+const IFF_IM2: u8 = 0x04;
+const IFF_2: u8 = 0x08;
+const IFF_EI: u8 = 0x20;
+const IFF_HALT: u8 = 0x80;
+
+// Converts a Meka state to a caduceus one
+pub fn import_meka(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut r = StateReader::new(data);
+
+    if r.raw(MAGIC.len()) != MAGIC {
+        return Err("not a Meka save state");
+    }
+    if r.u8() != VERSION {
+        return Err("unsupported Meka version");
+    }
+    if r.u8() != DRIVER_SMS {
+        return Err("only Master System states are supported");
+    }
+
+    let crc = r.u32();
+
+    let mut z80 = StateReader::new(r.raw(Z80_SIZE));
+    let mut pairs = [0; 12];
+    for pair in pairs.iter_mut() {
+        *pair = z80.u16();
+    }
+    let [af, bc, de, hl, ix, iy, pc, sp, af_, bc_, de_, hl_] = pairs;
+    let iff = z80.u8();
+    let i = z80.u8();
+    let refresh = z80.u8();
+    let refresh7 = z80.u8();
+
+    let mut machine = StateReader::new(r.raw(MACHINE_SIZE));
+    let regs = machine.raw(16);
+    let pram_address = machine.u8();
+    machine.u8();
+    let vdp_address = machine.u16();
+    let access_mode = machine.u8();
+    let access_first = machine.u8();
+    let read_latch = machine.u8();
+    let pal = machine.u8() != 0;
+    machine.raw(4);
+    let lines_left = machine.u32();
+    let need_hblank = machine.bool();
+    let need_vblank = machine.bool();
+    let glasses = machine.u8();
+
+    let mapper_regs = r.raw(4);
+    let ram = r.raw(0x2000);
+    let vram = r.raw(0x4000);
+    let cram = r.raw(32);
+
+    let mut psg = StateReader::new(r.raw(PSG_SIZE));
+    let mut psg_regs = [0; 8];
+    for reg in psg_regs.iter_mut() {
+        *reg = psg.u32();
+    }
+    let latched = psg.u32();
+    let noise = psg.u32();
+
+    if r.raw(3) != b"EOF" {
+        return Err("truncated");
+    }
+
+    let mut w = StateWriter::new();
+    w.header();
+
+    w.chunk(b"INFO", |w| {
+        for &slot_crc in &[0, crc, 0, 0] {
+            w.u32(slot_crc);
+        }
+    });
+
+    w.chunk(b"CPU ", |w| {
+        for &pair in &[af, bc, de, hl, af_, bc_, de_, hl_] {
+            w.u8((pair >> 8) as u8);
+            w.u8(pair as u8);
+        }
+        w.u8(i);
+        w.u8((refresh & 0x7f) | (refresh7 & 0x80));
+
+        // The Z80 core leaves PC on a HALT until it's interrupted
+        let halted = iff & IFF_HALT != 0;
+        let pc = if halted { pc.wrapping_add(1) } else { pc };
+
+        for &val in &[ix, iy, sp, pc] {
+            w.u16(val);
+        }
+
+        w.bool(iff & IFF_1 != 0);
+        w.bool(iff & IFF_2 != 0);
+        w.u8(if iff & IFF_IM2 != 0 {
+            2
+        } else if iff & IFF_IM1 != 0 {
+            1
+        } else {
+            0
+        });
+        w.bool(iff & IFF_EI != 0);
+        w.bool(halted);
+        w.u64(0);
+    });
+
+    w.chunk(b"BUS ", |w| {
+        // Meka runs with the BIOS already out of the way, only the
+        // cartridge, RAM and I/O enabled, and the controller pins as inputs
+        w.u8(0xab);
+        w.u8(0xff);
+        w.bytes(ram);
+        w.u8(match glasses {
+            0 => 0,
+            _ if glasses & 1 != 0 => 2,
+            _ => 1,
+        });
+        w.bool(false);
+        for _ in 0..3 {
+            w.u64(0);
+        }
+    });
+
+    w.chunk(b"VDP ", |w| {
+        w.bytes(vram);
+        w.bytes(cram);
+        w.bytes(&regs[..11]);
+        w.u16(if pal { pram_address as u16 & 0x1f } else { vdp_address & 0x3fff });
+        w.u8(if pal { 3 } else { 1 });
+        w.bool(access_mode != 0);
+        w.u8(access_first);
+        w.u8(read_latch);
+        w.u8(if need_vblank { 0x80 } else { 0 });
+        w.bool(need_hblank);
+        w.u8(lines_left as u8);
+        w.u8(regs[9]);
+    });
+
+    w.chunk(b"PSG ", |w| {
+        for channel in 0..4 {
+            w.u16(psg_regs[channel * 2] as u16 & 0x3ff);
+            w.u8(psg_regs[channel * 2 + 1] as u8 & 0x0f);
+            w.u16(0);
+            w.bool(true);
+        }
+
+        w.u16(noise as u16);
+        w.u8((latched >> 1) as u8 & 0x03);
+        w.bool(latched & 1 != 0);
+    });
+
+    w.chunk(b"SLT1", |w| {
+        for &bank in &mapper_regs[1..] {
+            w.u16(bank as u16);
+        }
+        w.u8(mapper_regs[0]);
+        w.bytes(&[]);
+    });
+
+    Ok(w.into_bytes())
+}
+
+// Converts a caduceus state to a Meka one
+pub fn export_meka(state: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let chunks = chunks(state)?;
+    let chunk = |tag: &[u8; 4]| {
+        chunks.iter()
+            .find(|(chunk, _)| chunk == tag)
+            .map(|(_, r)| r.clone())
+    };
+
+    let mut info = chunk(b"INFO").ok_or("no media information")?;
+    let mut cpu = chunk(b"CPU ").ok_or("no CPU state")?;
+    let mut bus = chunk(b"BUS ").ok_or("no bus state")?;
+    let mut vdp = chunk(b"VDP ").ok_or("no VDP state")?;
+    let mut psg = chunk(b"PSG ").ok_or("no PSG state")?;
+    let mut mapper = chunk(b"SLT1").ok_or("Meka states need a cartridge")?;
+
+    let mut w = StateWriter::new();
+    w.raw(MAGIC);
+    w.u8(VERSION);
+    w.u8(DRIVER_SMS);
+
+    info.u32();
+    w.u32(info.u32());
+
+    let mut regs8 = [0; 18];
+    for reg in regs8.iter_mut() {
+        *reg = cpu.u8();
+    }
+    let [a, f, b, c, d, e, h, l, a_, f_, b_, c_, d_, e_, h_, l_, i, refresh] = regs8;
+    let (ix, iy, sp, pc) = (cpu.u16(), cpu.u16(), cpu.u16(), cpu.u16());
+    let iff1 = cpu.bool();
+    let iff2 = cpu.bool();
+    let interrupt_mode = cpu.u8();
+    let ei_delay = cpu.bool();
+    let halted = cpu.bool();
+
+    let pair = |hi: u8, lo: u8| (hi as u16) << 8 | lo as u16;
+    let pc = if halted { pc.wrapping_sub(1) } else { pc };
+
+    let mut z80 = StateWriter::new();
+    for &val in &[pair(a, f), pair(b, c), pair(d, e), pair(h, l), ix, iy, pc, sp,
+                  pair(a_, f_), pair(b_, c_), pair(d_, e_), pair(h_, l_)] {
+        z80.u16(val);
+    }
+
+    let mut iff = 0;
+    for &(set, bit) in &[(iff1, IFF_1), (iff2, IFF_2), (interrupt_mode == 1, IFF_IM1),
+                         (interrupt_mode == 2, IFF_IM2), (ei_delay, IFF_EI), (halted, IFF_HALT)] {
+        if set {
+            iff |= bit;
+        }
+    }
+
+    z80.u8(iff);
+    z80.u8(i);
+    z80.u8(refresh);
+    z80.u8(refresh & 0x80);
+    w.raw(&sized(z80.into_bytes(), Z80_SIZE));
+
+    bus.u8();
+    bus.u8();
+    let ram = bus.bytes();
+    let shutter = bus.u8();
+
+    let vram = vdp.bytes();
+    let cram = vdp.bytes();
+    let regs = vdp.bytes();
+    let address = vdp.u16();
+    let code = vdp.u8();
+    let latched = vdp.bool();
+    let latch = vdp.u8();
+    let read_buffer = vdp.u8();
+    let status = vdp.u8();
+    let line_interrupt = vdp.bool();
+    let line_counter = vdp.u8();
+
+    let mut machine = StateWriter::new();
+    machine.raw(&sized(regs.to_vec(), 16));
+    machine.u8(if code == 3 { address as u8 & 0x1f } else { 0 });
+    machine.u8(0);
+    machine.u16(address);
+    machine.bool(latched);
+    machine.u8(latch);
+    machine.u8(read_buffer);
+    machine.bool(code == 3);
+    machine.raw(&[0; 4]);
+    machine.u32(line_counter as u32);
+    machine.bool(line_interrupt);
+    machine.bool(status & 0x80 != 0);
+    machine.u8(if shutter == 2 { 1 } else { 0 });
+    w.raw(&sized(machine.into_bytes(), MACHINE_SIZE));
+
+    let (bank0, bank1, bank2) = (mapper.u16(), mapper.u16(), mapper.u16());
+    w.u8(mapper.u8());
+    for &bank in &[bank0, bank1, bank2] {
+        w.u8(bank as u8);
+    }
+
+    w.raw(&sized(ram.to_vec(), 0x2000));
+    w.raw(&sized(vram.to_vec(), 0x4000));
+    w.raw(&sized(cram.to_vec(), 32));
+
+    let mut tones = [0; 4];
+    let mut psg_regs = StateWriter::new();
+    for tone in tones.iter_mut() {
+        *tone = psg.u16();
+        psg_regs.u32(*tone as u32);
+        psg_regs.u32(psg.u8() as u32);
+        psg.u16();
+        psg.bool();
+    }
+    let noise = psg.u16();
+    let latched = psg.u8();
+    let latched_volume = psg.bool();
+
+    // The noise channel's period, either fixed or following tone 2
+    let noise_freq = match tones[3] & 0x03 {
+        3 => tones[2] as u32,
+        rate => 0x10 << rate,
+    };
+
+    psg_regs.u32((latched as u32) << 1 | latched_volume as u32);
+    psg_regs.u32(noise as u32);
+    psg_regs.u32(noise_freq);
+    w.raw(&sized(psg_regs.into_bytes(), PSG_SIZE));
+
+    w.raw(b"EOF");
+
+    Ok(w.into_bytes())
+}
+
+// Pads or cuts a field to the size Meka uses
+fn sized(mut field: Vec<u8>, len: usize) -> Vec<u8> {
+    field.resize(len, 0);
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A state laid out the way Meka writes them, with every field in the
+    // form Meka itself leaves it so it survives the trip through caduceus's
+    // format byte for byte
+    fn meka_state() -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.raw(MAGIC);
+        w.u8(VERSION);
+        w.u8(DRIVER_SMS);
+        w.u32(0xdead_beef);
+
+        let mut z80 = StateWriter::new();
+        for &pair in &[0x12c5, 0x3456, 0x789a, 0xc000, 0x1111, 0x2222, 0x0150, 0xdfe0,
+                       0xaa01, 0xbb02, 0xcc03, 0xdd04] {
+            z80.u16(pair);
+        }
+        z80.u8(IFF_1 | IFF_2 | IFF_IM1);
+        z80.u8(0x3f);
+        z80.u8(0x85);
+        z80.u8(0x80);
+        w.raw(&sized(z80.into_bytes(), Z80_SIZE));
+
+        let mut machine = StateWriter::new();
+        machine.raw(&[0x36, 0xe0, 0xff, 0xff, 0xff, 0xff, 0xfb, 0x00, 0x00, 0x00, 0xff, 0, 0, 0, 0, 0]);
+        machine.u8(0);
+        machine.u8(0);
+        machine.u16(0x3800);
+        machine.u8(1);
+        machine.u8(0x38);
+        machine.u8(0x42);
+        machine.u8(0);
+        machine.raw(&[0; 4]);
+        machine.u32(0xff);
+        machine.bool(true);
+        machine.bool(true);
+        machine.u8(1);
+        w.raw(&sized(machine.into_bytes(), MACHINE_SIZE));
+
+        w.raw(&[0x80, 0, 1, 2]);
+        w.raw(&(0..0x2000).map(|i| i as u8).collect::<Vec<_>>());
+        w.raw(&(0..0x4000).map(|i| (i >> 3) as u8).collect::<Vec<_>>());
+        w.raw(&(0..32).map(|i| i as u8 & 0x3f).collect::<Vec<_>>());
+
+        let mut psg = StateWriter::new();
+        for &reg in &[0x100, 0x0f, 0x200, 0x08, 0x3ff, 0x00, 0x05, 0x0a] {
+            psg.u32(reg);
+        }
+        psg.u32(0x05);
+        psg.u32(0x8000);
+        psg.u32(0x20);
+        w.raw(&sized(psg.into_bytes(), PSG_SIZE));
+
+        w.raw(b"EOF");
+        w.into_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let meka = meka_state();
+        let state = import_meka(&meka).unwrap();
+
+        assert_eq!(export_meka(&state).unwrap(), meka);
+    }
+
+    #[test]
+    fn import_fills_in_caduceus_chunks() {
+        let state = import_meka(&meka_state()).unwrap();
+        let chunks = chunks(&state).unwrap();
+        let chunk = |tag: &[u8; 4]| chunks.iter().find(|(chunk, _)| chunk == tag).unwrap().1.clone();
+
+        let mut info = chunk(b"INFO");
+        assert_eq!((info.u32(), info.u32()), (0, 0xdead_beef));
+
+        let mut cpu = chunk(b"CPU ");
+        assert_eq!(cpu.raw(18), &[0x12, 0xc5, 0x34, 0x56, 0x78, 0x9a, 0xc0, 0x00,
+                                  0xaa, 0x01, 0xbb, 0x02, 0xcc, 0x03, 0xdd, 0x04, 0x3f, 0x85]);
+        assert_eq!((cpu.u16(), cpu.u16(), cpu.u16(), cpu.u16()), (0x1111, 0x2222, 0xdfe0, 0x0150));
+        assert_eq!((cpu.bool(), cpu.bool(), cpu.u8()), (true, true, 1));
+
+        let mut mapper = chunk(b"SLT1");
+        assert_eq!((mapper.u16(), mapper.u16(), mapper.u16(), mapper.u8()), (0, 1, 2, 0x80));
+    }
+
+    #[test]
+    fn bad_states_are_rejected() {
+        let meka = meka_state();
+
+        assert_eq!(import_meka(&meka[..meka.len() - 1]), Err("truncated"));
+        assert_eq!(import_meka(b"MEKA\x1a\x0d\x00"), Err("unsupported Meka version"));
+        assert_eq!(import_meka(b"MEKA\x1a\x0e\x01"), Err("only Master System states are supported"));
+    }
+}
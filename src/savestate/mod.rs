@@ -0,0 +1,327 @@
+// Save states are a magic number and format version, followed by chunks
+// each holding the state of one part of the machine:
+//
+//   "CADUCEUS" u16 version
+//   [4 byte tag][u32 length][length bytes] ...
+//
+// Everything is little endian. Readers skip chunks they don't know, and
+// fields are only ever added to the end of a chunk, with readers taking zero
+// for any missing from an older state. That way states stay loadable in both
+// directions as the format grows.
+
+mod meka;
+
+pub use self::meka::{export_meka, import_meka};
+
+const MAGIC: &[u8; 8] = b"CADUCEUS";
+// Only changed when a state can no longer be read by older versions
+const VERSION: u16 = 1;
+
+// Save state formats caduceus recognises, its own and other emulators'
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFormat {
+    Caduceus,
+    // Meka's .S00-.S09 files
+    Meka,
+    // Genesis Plus GX's .gp0-.gp9 files, which store its internal structures
+    // as they are laid out in memory, so their layout depends on the version
+    // and build. Recognised to give a proper error, but not supported.
+    GenesisPlusGx,
+}
+
+impl StateFormat {
+    pub fn detect(data: &[u8]) -> Option<StateFormat> {
+        if data.starts_with(MAGIC) {
+            Some(StateFormat::Caduceus)
+        } else if data.starts_with(meka::MAGIC) {
+            Some(StateFormat::Meka)
+        } else if data.starts_with(b"GENPLUS-GX") {
+            Some(StateFormat::GenesisPlusGx)
+        } else {
+            None
+        }
+    }
+}
+
+// Splits a caduceus state into its chunks, after checking the header
+pub fn chunks(data: &[u8]) -> Result<Vec<([u8; 4], StateReader<'_>)>, &'static str> {
+    let mut r = StateReader::new(data);
+    r.header()?;
+
+    let mut chunks = Vec::new();
+    while let Some(chunk) = r.chunk() {
+        chunks.push(chunk);
+    }
+
+    if !r.is_empty() {
+        return Err("truncated");
+    }
+
+    Ok(chunks)
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn header(&mut self) {
+        self.buf.extend_from_slice(MAGIC);
+        self.u16(VERSION);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Written as is, for formats with fixed size fields
+    pub fn raw(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    // Written with its length, so it can grow in later versions
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.buf.extend_from_slice(val);
+    }
+
+    pub fn chunk<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F) {
+        let mut chunk = StateWriter::new();
+        write(&mut chunk);
+
+        self.buf.extend_from_slice(tag);
+        self.bytes(&chunk.buf);
+    }
+}
+
+#[derive(Clone)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    // Checks the magic number and version at the start of a state
+    pub fn header(&mut self) -> Result<(), &'static str> {
+        if self.take(MAGIC.len()) != MAGIC {
+            return Err("not a caduceus save state");
+        }
+        if self.u16() != VERSION {
+            return Err("unsupported version");
+        }
+
+        Ok(())
+    }
+
+    // Reading past the end gives zeros, for fields newer than the state
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let start = self.pos.min(self.data.len());
+        let end = (self.pos + len).min(self.data.len());
+        self.pos += len;
+
+        &self.data[start..end]
+    }
+
+    fn array<T: AsMut<[u8]> + Default>(&mut self, len: usize) -> T {
+        let mut array = T::default();
+        let bytes = self.take(len);
+        array.as_mut()[..bytes.len()].copy_from_slice(bytes);
+
+        array
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.array::<[u8; 1]>(1)[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.array(2))
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array(4))
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array(8))
+    }
+
+    // A fixed size field, cut short at the end of the data
+    pub fn raw(&mut self, len: usize) -> &'a [u8] {
+        self.take(len)
+    }
+
+    // Everything left unread
+    pub fn rest(&mut self) -> &'a [u8] {
+        let len = self.data.len().saturating_sub(self.pos);
+
+        self.take(len)
+    }
+
+    pub fn bytes(&mut self) -> &'a [u8] {
+        let len = self.u32() as usize;
+
+        self.take(len)
+    }
+
+    // Copies a block written with bytes into dst. A shorter block leaves the
+    // rest of dst alone, a longer one is cut short.
+    pub fn bytes_into(&mut self, dst: &mut [u8]) {
+        let src = self.bytes();
+        let len = src.len().min(dst.len());
+
+        dst[..len].copy_from_slice(&src[..len]);
+    }
+
+    // The next chunk's tag and contents, None at the end of the state or at
+    // a chunk that runs past it, which is_empty then shows
+    pub fn chunk(&mut self) -> Option<([u8; 4], StateReader<'a>)> {
+        let start = self.pos;
+
+        if start + 8 > self.data.len() {
+            return None;
+        }
+
+        let tag = self.array::<[u8; 4]>(4);
+        let len = self.u32() as usize;
+
+        if len > self.data.len() - self.pos {
+            self.pos = start;
+            return None;
+        }
+
+        Some((tag, StateReader::new(self.take(len))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::Slot;
+    use error::Error;
+    use test_support::TestMachine;
+
+    #[test]
+    fn fields_round_trip() {
+        let mut w = StateWriter::new();
+        w.header();
+        w.chunk(b"TEST", |w| {
+            w.u8(0x12);
+            w.bool(true);
+            w.u16(0x3456);
+            w.u32(0x789a_bcde);
+            w.u64(0x0123_4567_89ab_cdef);
+            w.bytes(b"abc");
+        });
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.header(), Ok(()));
+
+        let (tag, mut chunk) = r.chunk().unwrap();
+        assert_eq!(&tag, b"TEST");
+        assert_eq!(chunk.u8(), 0x12);
+        assert!(chunk.bool());
+        assert_eq!(chunk.u16(), 0x3456);
+        assert_eq!(chunk.u32(), 0x789a_bcde);
+        assert_eq!(chunk.u64(), 0x0123_4567_89ab_cdef);
+        assert_eq!(chunk.bytes(), b"abc");
+
+        // Fields newer than the state read as zero
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.u32(), 0);
+
+        assert!(r.chunk().is_none());
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn chunk_running_past_the_end_is_left_unread() {
+        let mut w = StateWriter::new();
+        w.header();
+        w.chunk(b"TEST", |w| w.u32(1));
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data[..data.len() - 1]);
+        assert_eq!(r.header(), Ok(()));
+        assert!(r.chunk().is_none());
+        assert!(!r.is_empty());
+
+        assert_eq!(StateReader::new(b"NOTASTATE").header(), Err("not a caduceus save state"));
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut vm = TestMachine::default().build();
+        for _ in 0..3 {
+            vm.run_frame().unwrap();
+        }
+        let saved = vm.save_state();
+
+        for _ in 0..3 {
+            vm.run_frame().unwrap();
+        }
+        let expected = vm.save_state();
+
+        vm.load_state(&saved).unwrap();
+        assert_eq!(vm.save_state(), saved);
+
+        for _ in 0..3 {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.save_state(), expected);
+    }
+
+    #[test]
+    fn bad_states_are_rejected() {
+        let mut vm = TestMachine::default().build();
+        let saved = vm.save_state();
+
+        match vm.load_state(&saved[..saved.len() - 1]) {
+            Err(Error::InvalidState { reason: "truncated" }) => {}
+            other => panic!("expected a truncated state, got {:?}", other),
+        }
+
+        match TestMachine::default().with_fill(0xff).build().load_state(&saved) {
+            Err(Error::StateMediaMismatch { slot: Slot::Cartridge }) => {}
+            other => panic!("expected a media mismatch, got {:?}", other),
+        }
+    }
+}
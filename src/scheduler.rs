@@ -0,0 +1,57 @@
+use savestate::{StateReader, StateWriter};
+
+// Keeps everything besides the CPU in step with it. Time is measured in CPU
+// T-states since power on. The CPU moves the clock forward as it runs, and
+// the other components are only run to catch up when something needs them
+// to be current: a port access, or an event that could change the interrupt
+// line falling due.
+pub struct Scheduler {
+    now: u64,
+    // Time the other components have been run up to
+    synced: u64,
+    // Time of the next event that needs the components run
+    next_event: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            now: 0,
+            synced: 0,
+            next_event: 0,
+        }
+    }
+
+    pub fn advance_to(&mut self, now: u64) {
+        self.now = self.now.max(now);
+    }
+
+    // Whether the next event has been reached, so the components need to run
+    pub fn due(&self) -> bool {
+        self.now >= self.next_event
+    }
+
+    // T-states the components need to run to catch up with the CPU
+    pub fn pending(&self) -> u32 {
+        (self.now - self.synced) as u32
+    }
+
+    // Marks the components as caught up, with the next event the given
+    // number of T-states away
+    pub fn synced(&mut self, next_event: u32) {
+        self.synced = self.now;
+        self.next_event = self.now + next_event as u64;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.now);
+        w.u64(self.synced);
+        w.u64(self.next_event);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.now = r.u64();
+        self.synced = r.u64().min(self.now);
+        self.next_event = r.u64();
+    }
+}
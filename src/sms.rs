@@ -1,28 +1,226 @@
-use cartridge::Cartridge;
-use bus::Bus;
+use cartridge::{Cartridge, MapperType};
+use error::Error;
+use glasses::{Eye, GlassesMode};
+use bus::{Bus, Slot};
+use input::{ControllerPortDevice, Port};
+use region::{Region, TvStandard};
+use system::System;
 use vm::VM;
 
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
 #[derive(Default)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct SMS {
     cartridge: Option<String>,
+    bios: Option<String>,
+    card: Option<String>,
+    expansion: Option<String>,
+    mapper: Option<MapperType>,
+    system: Option<System>,
+    region: Option<Region>,
+    tv_standard: Option<TvStandard>,
+    strict: bool,
+    sample_rate: Option<u32>,
+    glasses_mode: Option<GlassesMode>,
+    fm: Option<bool>,
+    controllers: [Option<Box<dyn ControllerPortDevice>>; 2],
 }
 
 impl SMS {
     pub fn with_cartridge(mut self, cart: Option<&str>) -> Self {
-        self.cartridge = match cart {
-            Some(s) => Some(s.to_owned()),
-            None => None,
-        };
+        self.cartridge = cart.map(|s| s.to_owned());
+
+        self
+    }
+
+    // Boots through a BIOS image, without one the system starts in the state
+    // the BIOS would leave it in
+    pub fn with_bios(mut self, bios: Option<&str>) -> Self {
+        self.bios = bios.map(|s| s.to_owned());
+
+        self
+    }
+
+    // Inserts a Sega Card into the card slot
+    pub fn with_card(mut self, card: Option<&str>) -> Self {
+        self.card = card.map(|s| s.to_owned());
+
+        self
+    }
+
+    pub fn with_expansion(mut self, expansion: Option<&str>) -> Self {
+        self.expansion = expansion.map(|s| s.to_owned());
+
+        self
+    }
+
+    // Forces the cartridge mapper, by default it comes from the game database
+    // or is guessed from the ROM size
+    pub fn with_mapper(mut self, mapper: Option<MapperType>) -> Self {
+        self.mapper = mapper;
+
+        self
+    }
+
+    // Forces the system being emulated, by default it's guessed from the ROM's
+    // file extension or header. Everything currently runs as a Master System.
+    pub fn with_system(mut self, system: Option<System>) -> Self {
+        self.system = system;
+
+        self
+    }
+
+    // Forces the console's region, by default it's taken from the game
+    // database or cartridge header
+    pub fn with_region(mut self, region: Option<Region>) -> Self {
+        self.region = region;
+
+        self
+    }
+
+    // Forces NTSC or PAL timing, by default it's taken from the game database
+    // or cartridge header, or NTSC is used
+    pub fn with_tv_standard(mut self, tv_standard: Option<TvStandard>) -> Self {
+        self.tv_standard = tv_standard;
+
+        self
+    }
+
+    // In strict mode opcodes the Z80 doesn't define are reported as errors
+    // rather than run as NOPs, and cartridges must pass the BIOS's checksum
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+
+        self
+    }
+
+    // Rate audio is generated at, 44.1kHz by default
+    pub fn with_sample_rate(mut self, sample_rate: Option<u32>) -> Self {
+        self.sample_rate = sample_rate;
+
+        self
+    }
+
+    // Picks how frames for the 3-D glasses are shown. By default games the
+    // database lists as needing the glasses are shown to the left eye only,
+    // so they're playable without shutter glasses.
+    pub fn with_glasses_mode(mut self, mode: Option<GlassesMode>) -> Self {
+        self.glasses_mode = mode;
+
+        self
+    }
+
+    // Fits the FM sound unit, by default only for games the database lists
+    // as using it. The YM2413 isn't emulated, so games that find the unit
+    // play their music on it silently, Some(false) keeps them on the PSG.
+    pub fn with_fm(mut self, fm: Option<bool>) -> Self {
+        self.fm = fm;
 
         self
     }
 
-    pub fn build(self) -> VM {
-        let input_file = self.cartridge.unwrap();
-        let cartridge = Cartridge::load(&input_file).unwrap();
+    // Plugs a device into a controller port, ports default to a joypad unless
+    // the game database lists a peripheral for port A
+    pub fn with_controller<D: ControllerPortDevice + 'static>(mut self, port: Port, device: D) -> Self {
+        self.controllers[port as usize] = Some(Box::new(device));
 
-        let bus = Bus::new(cartridge);
+        self
+    }
+
+    pub fn build(self) -> Result<VM, Error> {
+        let mut cartridge = load(self.cartridge)?;
+        let card = load(self.card)?;
+        let expansion = load(self.expansion)?;
+        let bios = load(self.bios)?;
+
+        if let (Some(cartridge), Some(mapper)) = (cartridge.as_mut(), self.mapper) {
+            cartridge.set_mapper_type(mapper);
+        }
+
+        // Defaults come from whichever game the BIOS would boot
+        let game = cartridge.as_ref().or(card.as_ref()).or(expansion.as_ref());
+
+        if game.is_none() && bios.is_none() {
+            return Err(Error::NoMedia);
+        }
+
+        for media in [&cartridge, &card, &expansion, &bios].iter().filter_map(|media| media.as_ref()) {
+            if media.rom_size() > media.mapper_type().max_rom_size() {
+                return Err(Error::UnsupportedMapper {
+                    mapper: media.mapper_type(),
+                    rom_size: media.rom_size(),
+                });
+            }
+        }
+
+        if self.strict {
+            if let Some(info) = game.and_then(|game| game.info()) {
+                if !info.checksum_valid() {
+                    return Err(Error::BadHeader {
+                        checksum: info.checksum,
+                        calculated_checksum: info.calculated_checksum,
+                    });
+                }
+            }
+        }
+
+        let system = self.system
+            .or_else(|| game.and_then(|game| game.system()))
+            .unwrap_or_default();
+        let region = self.region
+            .or_else(|| game.and_then(|game| game.region()))
+            .unwrap_or_default();
+        let tv_standard = self.tv_standard
+            .or_else(|| game.and_then(|game| game.tv_standard()))
+            .unwrap_or_default();
+        let peripheral = game
+            .and_then(|game| game.game())
+            .and_then(|game| game.peripheral);
+        let glasses = game
+            .and_then(|game| game.game())
+            .and_then(|game| game.glasses)
+            .unwrap_or(false);
+        let fm = self.fm
+            .or_else(|| game.and_then(|game| game.game()).and_then(|game| game.fm))
+            .unwrap_or(false);
+        let glasses_mode = self.glasses_mode.unwrap_or(if glasses {
+            GlassesMode::SingleEye(Eye::Left)
+        } else {
+            GlassesMode::default()
+        });
+
+        let sample_rate = self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+
+        let mut bus = Bus::new(system, region, tv_standard, sample_rate);
+        bus.set_fm(fm);
+
+        bus.insert(Slot::Bios, bios);
+        bus.insert(Slot::Cartridge, cartridge);
+        bus.insert(Slot::Card, card);
+        bus.insert(Slot::Expansion, expansion);
+
+        let [a, b] = self.controllers;
+        if let Some(device) = a.or_else(|| peripheral.map(|kind| kind.create())) {
+            bus.connect(Port::A, device);
+        }
+        if let Some(device) = b {
+            bus.connect(Port::B, device);
+        }
+
+        let mut vm = VM::new(bus, self.strict);
+        vm.set_glasses_mode(glasses_mode);
+
+        Ok(vm)
+    }
+}
 
-        VM::new(bus)
+fn load(file: Option<String>) -> Result<Option<Cartridge>, Error> {
+    match file {
+        Some(path) => match Cartridge::load(&path) {
+            Ok(cartridge) => Ok(Some(cartridge)),
+            Err(source) => Err(Error::RomLoad { path, source }),
+        },
+        None => Ok(None),
     }
 }
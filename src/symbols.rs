@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use cpu::{Instruction, Memory};
+
+// A label from a symbol file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Symbol {
+    name: String,
+    // ROM bank the label is in, None when it isn't banked
+    bank: Option<usize>,
+}
+
+// Labels from the assembler or compiler that built a ROM, so addresses can
+// be shown by name
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<u16, Vec<Symbol>>,
+    by_name: HashMap<String, (u16, Option<usize>)>,
+}
+
+impl Symbols {
+    // Loads a WLA-DX .sym, SDCC .noi or SDCC linker .map file, picked by the
+    // file extension
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Symbols> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        match extension.as_deref() {
+            Some("sym") => Ok(Symbols::parse_wla(&text)),
+            Some("noi") => Ok(Symbols::parse_noi(&text)),
+            Some("map") => Ok(Symbols::parse_map(&text)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unknown symbol file type {}", path.display()))),
+        }
+    }
+
+    // Looks for a symbol file next to a ROM, with the same name and any of
+    // the supported extensions
+    pub fn find_for_rom<P: AsRef<Path>>(rom: P) -> Option<Symbols> {
+        ["sym", "noi", "map"].iter()
+            .map(|extension| rom.as_ref().with_extension(extension))
+            .filter(|path| path.is_file())
+            .filter_map(|path| Symbols::load(path).ok())
+            .next()
+    }
+
+    // WLA-DX symbol files list labels as "bank:address name" under [labels]
+    pub fn parse_wla(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+        let mut in_labels = false;
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+
+            if line.starts_with('[') {
+                in_labels = line == "[labels]";
+                continue;
+            }
+
+            if !in_labels {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+
+            let mut location = location.splitn(2, ':');
+            let bank = location.next().and_then(|bank| usize::from_str_radix(bank, 16).ok());
+            let addr = location.next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.insert(name, addr, Some(bank));
+            }
+        }
+
+        symbols
+    }
+
+    // SDCC .noi files have a "DEF name 0xaddress" line per symbol
+    pub fn parse_noi(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+
+        for line in text.lines() {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+
+            if let ["DEF", name, value] = parts[..] {
+                if let Some(value) = parse_sdcc_value(value) {
+                    symbols.insert_sdcc(name, value);
+                }
+            }
+        }
+
+        symbols
+    }
+
+    // SDCC linker maps list symbols as "address name" lines, with the
+    // address in hex. Some versions put the area's type before the address,
+    // either joined to it as "C:00000150" or on its own as "C:   00000150".
+    pub fn parse_map(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+
+        for line in text.lines() {
+            let mut parts = line.split_whitespace().collect::<Vec<_>>();
+            if parts.first().is_some_and(|part| part.len() == 2 && part.ends_with(':')) {
+                parts.remove(0);
+            }
+
+            if parts.len() < 2 {
+                continue;
+            }
+
+            let value = u32::from_str_radix(parts[0].trim_start_matches("C:"), 16);
+            let name = parts[1];
+            let is_symbol = name.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic());
+
+            if let (Ok(value), true) = (value, is_symbol) {
+                symbols.insert_sdcc(name, value);
+            }
+        }
+
+        symbols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // Address of a label and the ROM bank it's in, for setting breakpoints
+    // by name
+    pub fn resolve(&self, name: &str) -> Option<(u16, Option<usize>)> {
+        self.by_name.get(name).cloned()
+    }
+
+    // The label at addr, in the given bank. Labels outside the banked area
+    // of ROM match whatever the bank.
+    pub fn lookup(&self, addr: u16, bank: Option<usize>) -> Option<&str> {
+        let symbols = self.by_addr.get(&addr)?;
+
+        symbols.iter()
+            .find(|symbol| symbol.bank.is_none() || bank.is_none() || symbol.bank == bank)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    // The label at addr in whichever bank is currently mapped there
+    pub fn label<M: Memory + ?Sized>(&self, memory: &M, addr: u16) -> Option<&str> {
+        self.lookup(addr, memory.bank(addr))
+    }
+
+    // Disassembly text with the branch target replaced by its label
+    pub fn annotate<M: Memory + ?Sized>(&self, memory: &M, instruction: &Instruction) -> String {
+        let label = instruction.target.and_then(|target| Some((target, self.label(memory, target)?)));
+
+        match label {
+            Some((target, label)) => instruction.text.replace(&format!("${:04x}", target), label),
+            None => instruction.text.clone(),
+        }
+    }
+
+    fn insert(&mut self, name: &str, addr: u16, bank: Option<usize>) {
+        // Banks only matter where the mapper pages ROM in
+        let bank = if addr < 0xc000 { bank } else { None };
+
+        self.by_addr.entry(addr).or_default().push(Symbol { name: name.to_string(), bank });
+        self.by_name.entry(name.to_string()).or_insert((addr, bank));
+    }
+
+    // SDCC puts the bank of banked symbols above the 16-bit address
+    fn insert_sdcc(&mut self, name: &str, value: u32) {
+        let bank = if value > 0xffff { Some((value >> 16) as usize) } else { None };
+
+        self.insert(name, value as u16, bank);
+    }
+}
+
+fn parse_sdcc_value(value: &str) -> Option<u32> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::disassemble;
+
+    #[test]
+    fn wla() {
+        let symbols = Symbols::parse_wla("\
+; WLA symbolic information file
+[labels]
+00:0000 boot
+01:8000 level_data ; comment
+00:c000 ram_start
+[definitions]
+00000010 not_a_label
+");
+
+        assert_eq!(symbols.resolve("boot"), Some((0x0000, Some(0))));
+        assert_eq!(symbols.resolve("level_data"), Some((0x8000, Some(1))));
+        // RAM isn't banked
+        assert_eq!(symbols.resolve("ram_start"), Some((0xc000, None)));
+        assert_eq!(symbols.resolve("not_a_label"), None);
+    }
+
+    #[test]
+    fn noi() {
+        let symbols = Symbols::parse_noi("\
+DEF _main 0x150
+DEF _banked_fn 0x28000
+DEF _far_fn 0x1a4000
+LOAD game.ihx
+");
+
+        assert_eq!(symbols.resolve("_main"), Some((0x0150, None)));
+        assert_eq!(symbols.resolve("_banked_fn"), Some((0x8000, Some(2))));
+        assert_eq!(symbols.resolve("_far_fn"), Some((0x4000, Some(0x1a))));
+        assert_eq!(symbols.resolve("LOAD"), None);
+    }
+
+    #[test]
+    fn map_address_forms() {
+        let symbols = Symbols::parse_map("\
+Area                       Addr        Size        Decimal Bytes (Attributes)
+--------------------       ----        ----        ------- ----- ------------
+_CODE                  00000150    00000200 =         512. bytes (REL,CON)
+
+      Value  Global           Global Defined In Module
+      -----  --------------------------------
+     00000150  _main              main
+     C:00000160  _joined          main
+     C:   00000170  _spaced       main
+     C:   00038000  _banked       level
+     00028000  _plain_banked      level
+");
+
+        assert_eq!(symbols.resolve("_main"), Some((0x0150, None)));
+        assert_eq!(symbols.resolve("_joined"), Some((0x0160, None)));
+        assert_eq!(symbols.resolve("_spaced"), Some((0x0170, None)));
+        assert_eq!(symbols.resolve("_banked"), Some((0x8000, Some(3))));
+        assert_eq!(symbols.resolve("_plain_banked"), Some((0x8000, Some(2))));
+
+        // Area headers and the table's own headings aren't symbols
+        assert_eq!(symbols.resolve("_CODE"), None);
+        assert_eq!(symbols.resolve("Value"), None);
+        assert_eq!(symbols.resolve("--------------------"), None);
+    }
+
+    #[test]
+    fn labels_by_bank() {
+        let symbols = Symbols::parse_wla("\
+[labels]
+02:8000 in_bank_2
+03:8000 in_bank_3
+00:0038 irq
+");
+
+        assert_eq!(symbols.lookup(0x8000, Some(2)), Some("in_bank_2"));
+        assert_eq!(symbols.lookup(0x8000, Some(3)), Some("in_bank_3"));
+        assert_eq!(symbols.lookup(0x8000, Some(4)), None);
+        // Without a bank to go on the first label is used
+        assert_eq!(symbols.lookup(0x8000, None), Some("in_bank_2"));
+        assert_eq!(symbols.lookup(0x0038, None), Some("irq"));
+    }
+
+    #[test]
+    fn annotate() {
+        let symbols = Symbols::parse_wla("[labels]\n00:1234 target\n");
+        let rom = [0xcd, 0x34, 0x12, 0xc3, 0x00, 0x10];
+
+        assert_eq!(symbols.annotate(&rom[..], &disassemble(&rom[..], 0)), "call target");
+        assert_eq!(symbols.annotate(&rom[..], &disassemble(&rom[..], 3)), "jp $1000");
+    }
+}
@@ -0,0 +1,26 @@
+use std::path::Path;
+
+// The consoles that run SMS-family software
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum System {
+    #[default]
+    Sms,
+    GameGear,
+    Sg1000,
+    Sc3000,
+}
+
+impl System {
+    // Guesses the system a ROM is for from its file extension
+    pub fn from_file_name<P: AsRef<Path>>(file_name: P) -> Option<System> {
+        let extension = file_name.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "sms" => Some(System::Sms),
+            "gg" => Some(System::GameGear),
+            "sg" => Some(System::Sg1000),
+            "sc" => Some(System::Sc3000),
+            _ => None,
+        }
+    }
+}
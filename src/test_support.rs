@@ -0,0 +1,78 @@
+// Machines for unit tests, built straight from a ROM image in memory so
+// tests don't need files on disk
+
+use bus::{Bus, Slot};
+use cartridge::Cartridge;
+use region::{Region, TvStandard};
+use system::System;
+use vm::VM;
+
+// Counts in BC forever, storing the low byte in RAM, so the machine is in a
+// different state at the end of every frame
+const COUNTER: [u8; 11] = [
+    0xf3,             // di
+    0x31, 0xf0, 0xdf, // ld sp,$dff0
+    0x03,             // inc bc
+    0x79,             // ld a,c
+    0x32, 0x00, 0xc0, // ld ($c000),a
+    0x18, 0xf9,       // jr $0004
+];
+
+pub struct TestMachine {
+    rom: Vec<u8>,
+}
+
+impl Default for TestMachine {
+    fn default() -> Self {
+        let mut rom = vec![0; 0x8000];
+        rom[..COUNTER.len()].copy_from_slice(&COUNTER);
+
+        TestMachine { rom }
+    }
+}
+
+impl TestMachine {
+    // Fills the ROM after the program, for a cartridge with another checksum
+    pub fn with_fill(mut self, fill: u8) -> Self {
+        for byte in &mut self.rom[COUNTER.len()..] {
+            *byte = fill;
+        }
+
+        self
+    }
+
+    // Cuts or pads the ROM to len bytes
+    pub fn with_size(mut self, len: usize) -> Self {
+        self.rom.resize(len, 0);
+        self
+    }
+
+    // Writes bytes into the ROM at offset, for headers and other fixtures
+    pub fn with_bytes(mut self, offset: usize, bytes: &[u8]) -> Self {
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub fn into_rom(self) -> Vec<u8> {
+        self.rom
+    }
+
+    pub fn build(self) -> VM {
+        let mut bus = Bus::new(System::Sms, Region::Export, TvStandard::Ntsc, 44100);
+        bus.insert(Slot::Cartridge, Some(Cartridge::from_bytes(&self.rom)));
+
+        VM::new(bus, false)
+    }
+
+    // The state at the end of each of the first frames
+    pub fn snapshots(self, frames: usize) -> Vec<Vec<u8>> {
+        let mut vm = self.build();
+
+        (0..frames)
+            .map(|_| {
+                vm.run_frame().unwrap();
+                vm.save_state()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use cpu::{disassemble, Flags, Memory, State};
+use symbols::Symbols;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    // Address, bytes and disassembly followed by the registers and the
+    // T-state count, one instruction per line with fixed columns
+    #[default]
+    Full,
+    // The "PC: disassembly" lines written by MAME's trace command
+    Mame,
+}
+
+// Logs instructions as they're executed, set on a VM with set_tracer
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    // Only instructions in these ranges are logged, all of them when empty
+    pc_ranges: Vec<RangeInclusive<u16>>,
+    // Only instructions in these ROM banks are logged, all of them when empty
+    banks: Vec<usize>,
+    // Logging starts once PC reaches start and finishes once it reaches stop
+    start: Option<u16>,
+    stop: Option<u16>,
+    active: bool,
+    // Labels to show in place of branch targets
+    symbols: Option<Symbols>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(out: W) -> Self {
+        Tracer {
+            out: Box::new(out),
+            format: TraceFormat::default(),
+            pc_ranges: Vec::new(),
+            banks: Vec::new(),
+            start: None,
+            stop: None,
+            active: true,
+            symbols: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+
+        Ok(Tracer::new(BufWriter::new(file)))
+    }
+
+    pub fn with_format(mut self, format: TraceFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Can be given more than once to log several ranges
+    pub fn with_pc_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.pc_ranges.push(range);
+        self
+    }
+
+    // Can be given more than once to log several banks. Code running from
+    // RAM is never in a bank, so isn't logged once a bank is given.
+    pub fn with_bank(mut self, bank: usize) -> Self {
+        self.banks.push(bank);
+        self
+    }
+
+    pub fn with_start(mut self, pc: Option<u16>) -> Self {
+        self.start = pc;
+        self.active = pc.is_none();
+        self
+    }
+
+    pub fn with_stop(mut self, pc: Option<u16>) -> Self {
+        self.stop = pc;
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: Option<Symbols>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Logs the instruction the CPU is about to execute
+    pub fn trace<M: Memory + ?Sized>(&mut self, state: &State, memory: &M) -> io::Result<()> {
+        let pc = state.pc;
+
+        if !self.active && self.start == Some(pc) {
+            self.active = true;
+        } else if self.active && self.stop == Some(pc) {
+            self.active = false;
+            self.out.flush()?;
+        }
+
+        if !self.active || !self.matches(pc, memory) {
+            return Ok(());
+        }
+
+        let instruction = disassemble(memory, pc);
+        let text = match self.symbols {
+            Some(ref symbols) => symbols.annotate(memory, &instruction),
+            None => instruction.text.clone(),
+        };
+
+        match self.format {
+            TraceFormat::Mame => writeln!(self.out, "{:04X}: {}", pc, text),
+            TraceFormat::Full => {
+                let bytes = instruction.bytes.iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                writeln!(self.out,
+                         "{:04X}  {:<12}{:<20}AF:{:02X}{:02X} BC:{:04X} DE:{:04X} HL:{:04X} IX:{:04X} IY:{:04X} SP:{:04X} F:{} CYC:{}",
+                         pc, bytes, text, state.a, state.f.bits(), state.bc(), state.de(),
+                         state.hl(), state.ix, state.iy, state.sp, flags(state.f), state.cycles)
+            }
+        }
+    }
+
+    fn matches<M: Memory + ?Sized>(&self, pc: u16, memory: &M) -> bool {
+        let in_range = self.pc_ranges.is_empty() ||
+            self.pc_ranges.iter().any(|range| range.contains(&pc));
+
+        let in_bank = self.banks.is_empty() ||
+            memory.bank(pc).is_some_and(|bank| self.banks.contains(&bank));
+
+        in_range && in_bank
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+// Flags from bit 7 down, as a letter when set and a dash when clear
+fn flags(f: Flags) -> String {
+    [(Flags::S, 'S'), (Flags::Z, 'Z'), (Flags::Y, 'Y'), (Flags::H, 'H'),
+     (Flags::X, 'X'), (Flags::P, 'P'), (Flags::N, 'N'), (Flags::C, 'C')]
+        .iter()
+        .map(|&(flag, name)| if f.contains(flag) { name } else { '-' })
+        .collect()
+}
@@ -0,0 +1,477 @@
+use region::TvStandard;
+use savestate::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 192;
+
+// CPU T-states per scanline, the VDP runs at 1.5 pixels per T-state
+const CYCLES_PER_LINE: u32 = 228;
+const PIXELS_PER_LINE: u32 = 342;
+
+bitflags! {
+    #[derive(Default)]
+    struct Status: u8 {
+        const SPRITE_COLLISION = 0b00100000;
+        const SPRITE_OVERFLOW = 0b01000000;
+        const FRAME_INTERRUPT = 0b10000000;
+    }
+}
+
+pub struct Vdp {
+    tv_standard: TvStandard,
+
+    vram: Box<[u8]>,
+    cram: [u8; 32],
+    regs: [u8; 11],
+
+    address: u16,
+    code: u8,
+    // First byte of a two byte control port write
+    control_latch: Option<u8>,
+    read_buffer: u8,
+
+    status: Status,
+    line_interrupt: bool,
+    line_counter: u8,
+    // Vertical scroll is only sampled at the start of each frame
+    vscroll: u8,
+    // Horizontal scroll is sampled at the start of each line
+    hscroll: u8,
+    // Colour of the sprite pixel at each point on the current line, 0 where
+    // there isn't one, found before the line starts
+    line_sprites: [u8; SCREEN_WIDTH],
+    // Next pixel of the current line to be drawn. Lines are drawn as the
+    // beam reaches each pixel, so changes partway along a line show up
+    // from where the beam was when they were made.
+    line_x: usize,
+
+    scanline: u16,
+    line_cycles: u32,
+    h_latch: u8,
+
+    framebuffer: Box<[u32]>,
+    // Set once the last active line of a frame has been rendered
+    frame_complete: bool,
+}
+
+impl Vdp {
+    pub fn new(tv_standard: TvStandard) -> Self {
+        Vdp {
+            tv_standard,
+
+            vram: vec![0; 0x4000].into_boxed_slice(),
+            cram: [0; 32],
+            regs: [0x36, 0x80, 0xff, 0xff, 0xff, 0xff, 0xfb, 0x00, 0x00, 0x00, 0xff],
+
+            address: 0,
+            code: 0,
+            control_latch: None,
+            read_buffer: 0,
+
+            status: Status::empty(),
+            line_interrupt: false,
+            line_counter: 0xff,
+            vscroll: 0,
+            hscroll: 0,
+            line_sprites: [0; SCREEN_WIDTH],
+            line_x: 0,
+
+            scanline: 0,
+            line_cycles: 0,
+            h_latch: 0,
+
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+            frame_complete: false,
+        }
+    }
+
+    // The framebuffer isn't saved, it's redrawn over the next frame
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.cram);
+        w.bytes(&self.regs);
+        w.u16(self.address);
+        w.u8(self.code);
+        w.bool(self.control_latch.is_some());
+        w.u8(self.control_latch.unwrap_or(0));
+        w.u8(self.read_buffer);
+        w.u8(self.status.bits());
+        w.bool(self.line_interrupt);
+        w.u8(self.line_counter);
+        w.u8(self.vscroll);
+        w.u16(self.scanline);
+        w.u32(self.line_cycles);
+        w.u8(self.h_latch);
+        w.u8(self.hscroll);
+        w.raw(&self.line_sprites);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        r.bytes_into(&mut self.vram);
+        r.bytes_into(&mut self.cram);
+        r.bytes_into(&mut self.regs);
+        self.address = r.u16() & 0x3fff;
+        self.code = r.u8() & 0x03;
+        let latched = r.bool();
+        let latch = r.u8();
+        self.control_latch = if latched { Some(latch) } else { None };
+        self.read_buffer = r.u8();
+        self.status = Status::from_bits_truncate(r.u8());
+        self.line_interrupt = r.bool();
+        self.line_counter = r.u8();
+        self.vscroll = r.u8();
+        self.scanline = r.u16() % self.tv_standard.lines_per_frame();
+        self.line_cycles = r.u32() % CYCLES_PER_LINE;
+        self.h_latch = r.u8();
+        self.hscroll = r.u8();
+        self.line_sprites = [0; SCREEN_WIDTH];
+        let sprites = r.raw(SCREEN_WIDTH);
+        self.line_sprites[..sprites.len()].copy_from_slice(sprites);
+
+        // What's already been drawn of the line isn't saved either
+        self.line_x = (self.beam_x() as usize).min(SCREEN_WIDTH);
+    }
+
+    pub fn tv_standard(&self) -> TvStandard {
+        self.tv_standard
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    // Returns true once per frame, when the framebuffer holds a complete frame
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+
+        complete
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.regs
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn cram(&self) -> &[u8] {
+        &self.cram
+    }
+
+    // The status register, without the side effects of reading it through
+    // the control port
+    pub fn status(&self) -> u8 {
+        self.status.bits() | 0x1f
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.framebuffer[y * SCREEN_WIDTH + x]
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // Horizontal position of the beam in pixels, measured from the left edge
+    // of the active display
+    pub fn beam_x(&self) -> u16 {
+        (self.line_cycles * PIXELS_PER_LINE / CYCLES_PER_LINE) as u16
+    }
+
+    // Position of the beam in pixels since the start of the frame
+    pub fn beam_position(&self) -> u32 {
+        self.scanline as u32 * PIXELS_PER_LINE + self.beam_x() as u32
+    }
+
+    pub fn pixels_per_frame(&self) -> u32 {
+        self.tv_standard.lines_per_frame() as u32 * PIXELS_PER_LINE
+    }
+
+    pub fn cycles_to_pixels(cycles: u32) -> u32 {
+        cycles * PIXELS_PER_LINE / CYCLES_PER_LINE
+    }
+
+    pub fn pixel_position(x: u16, line: u16) -> u32 {
+        line as u32 * PIXELS_PER_LINE + x as u32
+    }
+
+    // T-states until the next line starts, which is when the interrupt
+    // flags can next change
+    pub fn cycles_to_next_line(&self) -> u32 {
+        CYCLES_PER_LINE - self.line_cycles
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        (self.status.contains(Status::FRAME_INTERRUPT) && self.regs[1] & 0x20 != 0) ||
+            (self.line_interrupt && self.regs[0] & 0x10 != 0)
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.line_cycles += cycles;
+
+        while self.line_cycles >= CYCLES_PER_LINE {
+            self.render_to(SCREEN_WIDTH);
+
+            self.line_cycles -= CYCLES_PER_LINE;
+            self.scanline = (self.scanline + 1) % self.tv_standard.lines_per_frame();
+            self.start_line();
+        }
+
+        let beam_x = self.beam_x() as usize;
+        self.render_to(beam_x);
+    }
+
+    fn start_line(&mut self) {
+        let line = self.scanline;
+
+        if line == 0 {
+            self.vscroll = self.regs[9];
+        }
+
+        if (line as usize) < SCREEN_HEIGHT {
+            self.hscroll = if line < 16 && self.regs[0] & 0x40 != 0 { 0 } else { self.regs[8] };
+            self.line_sprites = [0; SCREEN_WIDTH];
+            self.line_x = 0;
+
+            if self.display_enabled() {
+                self.find_sprites(line as usize);
+            }
+        }
+
+        if line as usize == SCREEN_HEIGHT {
+            self.status.insert(Status::FRAME_INTERRUPT);
+            self.frame_complete = true;
+        }
+
+        if line as usize <= SCREEN_HEIGHT {
+            if self.line_counter == 0 {
+                self.line_counter = self.regs[10];
+                self.line_interrupt = true;
+            } else {
+                self.line_counter -= 1;
+            }
+        } else {
+            self.line_counter = self.regs[10];
+        }
+    }
+
+    pub fn v_counter(&self) -> u8 {
+        self.tv_standard.v_counter(self.scanline)
+    }
+
+    pub fn h_counter(&self) -> u8 {
+        self.h_latch
+    }
+
+    // Latches the H counter as it would read with the beam at pixel x
+    pub fn latch_h_counter(&mut self, x: u16) {
+        // The counter runs 0x00-0x93 then jumps to 0xE9-0xFF
+        let count = x / 2;
+
+        self.h_latch = if count > 0x93 {
+            (count + 0xe9 - 0x94) as u8
+        } else {
+            count as u8
+        };
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let val = self.read_buffer;
+
+        self.control_latch = None;
+        self.read_buffer = self.vram[self.address as usize];
+        self.address = (self.address + 1) & 0x3fff;
+
+        val
+    }
+
+    pub fn read_control(&mut self) -> u8 {
+        let val = self.status();
+
+        self.control_latch = None;
+        self.status = Status::empty();
+        self.line_interrupt = false;
+
+        val
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.control_latch = None;
+
+        if self.code == 3 {
+            self.cram[self.address as usize & 0x1f] = val;
+        } else {
+            self.vram[self.address as usize] = val;
+        }
+
+        self.read_buffer = val;
+        self.address = (self.address + 1) & 0x3fff;
+    }
+
+    pub fn write_control(&mut self, val: u8) {
+        let low = match self.control_latch.take() {
+            Some(low) => low,
+            None => {
+                self.control_latch = Some(val);
+                self.address = (self.address & 0x3f00) | val as u16;
+                return;
+            }
+        };
+
+        self.code = val >> 6;
+        self.address = (((val & 0x3f) as u16) << 8) | low as u16;
+
+        match self.code {
+            0 => {
+                self.read_buffer = self.vram[self.address as usize];
+                self.address = (self.address + 1) & 0x3fff;
+            }
+            2 => {
+                let reg = (val & 0x0f) as usize;
+                if reg < self.regs.len() {
+                    self.regs[reg] = low;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn colour(&self, index: usize) -> u32 {
+        // CRAM entries are --BBGGRR
+        let c = self.cram[index & 0x1f] as u32;
+        let r = (c & 0x03) * 0x55;
+        let g = ((c >> 2) & 0x03) * 0x55;
+        let b = ((c >> 4) & 0x03) * 0x55;
+
+        (r << 16) | (g << 8) | b
+    }
+
+    fn tile_pixel(&self, pattern: usize, row: usize, column: usize) -> usize {
+        let base = pattern * 32 + row * 4;
+        let bit = 7 - column;
+
+        (0..4).fold(0, |acc, plane| {
+            acc | ((((self.vram[(base + plane) & 0x3fff] >> bit) & 1) as usize) << plane)
+        })
+    }
+
+    fn display_enabled(&self) -> bool {
+        self.regs[1] & 0x40 != 0
+    }
+
+    // Draws the current line up to, but not including, pixel end
+    fn render_to(&mut self, end: usize) {
+        let line = self.scanline as usize;
+        if line >= SCREEN_HEIGHT {
+            return;
+        }
+
+        while self.line_x < end.min(SCREEN_WIDTH) {
+            let x = self.line_x;
+            let colour = self.colour(self.pixel_index(line, x));
+
+            self.framebuffer[line * SCREEN_WIDTH + x] = colour;
+            self.line_x += 1;
+        }
+    }
+
+    // The CRAM entry shown at a point on the screen
+    fn pixel_index(&self, line: usize, x: usize) -> usize {
+        let backdrop = 16 + (self.regs[7] & 0x0f) as usize;
+
+        if !self.display_enabled() || (x < 8 && self.regs[0] & 0x20 != 0) {
+            return backdrop;
+        }
+
+        let (index, priority) = self.background_pixel(line, x);
+        let sprite = self.line_sprites[x] as usize;
+
+        if sprite != 0 && !priority {
+            16 + sprite
+        } else {
+            index
+        }
+    }
+
+    // The background's CRAM entry at a point, and whether it's a non-zero
+    // pixel drawn in front of sprites
+    fn background_pixel(&self, line: usize, x: usize) -> (usize, bool) {
+        let name_table = ((self.regs[2] & 0x0e) as usize) << 10;
+
+        let vscroll = if x >= 192 && self.regs[0] & 0x80 != 0 { 0 } else { self.vscroll };
+        let y = (line + vscroll as usize) % 224;
+        let sx = (x as u8).wrapping_sub(self.hscroll) as usize;
+
+        let entry = name_table + ((y / 8) * 32 + sx / 8) * 2;
+        let lo = self.vram[entry] as usize;
+        let hi = self.vram[entry + 1] as usize;
+
+        let pattern = ((hi & 0x01) << 8) | lo;
+        let row = if hi & 0x04 != 0 { 7 - y % 8 } else { y % 8 };
+        let column = if hi & 0x02 != 0 { 7 - sx % 8 } else { sx % 8 };
+        let palette = if hi & 0x08 != 0 { 16 } else { 0 };
+
+        let value = self.tile_pixel(pattern, row, column);
+
+        (palette + value, hi & 0x10 != 0 && value != 0)
+    }
+
+    // Fills in the sprite pixels for a line, as the VDP does while the line
+    // before it is drawn
+    fn find_sprites(&mut self, line: usize) {
+        let sat = ((self.regs[5] & 0x7e) as usize) << 7;
+        let height = if self.regs[1] & 0x02 != 0 { 16 } else { 8 };
+        let shift = if self.regs[0] & 0x08 != 0 { 8 } else { 0 };
+        let pattern_base = if self.regs[6] & 0x04 != 0 { 256 } else { 0 };
+
+        let mut count = 0;
+
+        for i in 0..64 {
+            let y = self.vram[sat + i] as usize;
+            if y == 0xd0 {
+                break;
+            }
+
+            // Sprites are drawn one line below their Y coordinate and wrap
+            // around the bottom of the screen
+            let top = (y + 1) & 0xff;
+            let row = (line + 256 - top) & 0xff;
+            if row >= height {
+                continue;
+            }
+
+            count += 1;
+            if count > 8 {
+                self.status.insert(Status::SPRITE_OVERFLOW);
+                break;
+            }
+
+            let x = self.vram[sat + 0x80 + i * 2] as usize;
+            let mut pattern = self.vram[sat + 0x81 + i * 2] as usize;
+            if height == 16 {
+                pattern &= !1;
+            }
+            let pattern = pattern_base + pattern + row / 8;
+
+            for column in 0..8 {
+                let sx = match (x + column).checked_sub(shift) {
+                    Some(sx) if sx < SCREEN_WIDTH => sx,
+                    _ => continue,
+                };
+
+                let value = self.tile_pixel(pattern, row % 8, column);
+                if value == 0 {
+                    continue;
+                }
+
+                if self.line_sprites[sx] != 0 {
+                    self.status.insert(Status::SPRITE_COLLISION);
+                    continue;
+                }
+                self.line_sprites[sx] = value as u8;
+            }
+        }
+    }
+}
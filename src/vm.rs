@@ -1,22 +1,488 @@
-use bus::Bus;
-use cpu::Cpu;
+use std::mem;
+
+use bus::{Bus, Slot};
+use cartridge::Cartridge;
+use cpu::{Cpu, Memory, Z80Bus};
+use error::Error;
+use glasses::{Glasses, GlassesMode};
+use input::{ControllerPortDevice, Port, PortInput};
+use movie::{Movie, MovieStatus};
+use rewind::Rewind;
+use savestate::{self, StateFormat, StateWriter};
+use system::System;
+use trace::Tracer;
+use watchpoints::Watchpoints;
+
+// The output of a single frame from run_frame
+pub struct Frame<'a> {
+    // Video output, width pixels wide
+    pub video: &'a [u32],
+    pub width: usize,
+    // Mono audio generated during the frame
+    pub audio: &'a [i16],
+}
+
+// Slots whose media has to match for a save state to load
+const SLOTS: [Slot; 4] = [Slot::Bios, Slot::Cartridge, Slot::Card, Slot::Expansion];
+
+// A movie being recorded or played back
+struct MovieSession {
+    movie: Movie,
+    recording: bool,
+    frame: usize,
+    desync: Option<usize>,
+}
 
 pub struct VM {
     bus: Bus,
     cpu: Cpu,
+    glasses: Glasses,
+    tracer: Option<Tracer>,
+    rewind: Option<Rewind>,
+    movie: Option<MovieSession>,
+    // The input last given to each port, for recording movies
+    inputs: [PortInput; 2],
+    // Set when an instruction completes a frame, until run_frame sees it
+    frame_ready: bool,
 }
 
 impl VM {
-    pub fn new(bus: Bus) -> VM {
+    // Starts execution in the BIOS if the bus has one, otherwise straight in
+    // the cartridge
+    pub(crate) fn new(mut bus: Bus, strict: bool) -> VM {
+        let mut cpu = Cpu::new(strict);
+
+        if !bus.has_bios() {
+            bus.skip_bios();
+
+            // The BIOS leaves the stack at the top of RAM and interrupts in
+            // mode 1 when it jumps into the cartridge
+            let state = cpu.state_mut();
+            state.sp = 0xdff0;
+            state.interrupt_mode = 1;
+        }
+
         VM {
-            bus: bus,
-            cpu: Cpu::new(),
+            bus,
+            cpu,
+            glasses: Glasses::new(GlassesMode::default()),
+            tracer: None,
+            rewind: None,
+            movie: None,
+            inputs: [PortInput::default(); 2],
+            frame_ready: false,
+        }
+    }
+
+    // Selects how frames for the 3-D glasses are presented
+    pub fn set_glasses_mode(&mut self, mode: GlassesMode) {
+        self.glasses.set_mode(mode);
+    }
+
+    pub fn glasses_mode(&self) -> GlassesMode {
+        self.glasses.mode()
+    }
+
+    // The most recent video output, frame_width() pixels wide
+    pub fn frame(&self) -> &[u32] {
+        self.glasses.output()
+    }
+
+    pub fn frame_width(&self) -> usize {
+        self.glasses.width()
+    }
+
+    // Audio generated since the last call to run_frame or clear_audio
+    pub fn audio(&self) -> &[i16] {
+        self.bus.psg().samples()
+    }
+
+    pub fn clear_audio(&mut self) {
+        self.bus.psg_mut().clear_samples();
+    }
+
+    // Text written to the SDSC debug console since the last clear_console
+    pub fn console(&self) -> &[u8] {
+        self.bus.console()
+    }
+
+    pub fn clear_console(&mut self) {
+        self.bus.clear_console();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.bus.psg().sample_rate()
+    }
+
+    pub fn system(&self) -> System {
+        self.bus.system()
+    }
+
+    // Direct access to the CPU, for debuggers
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    // Writes to memory as the CPU would, reads go through the Memory trait
+    pub fn poke8(&mut self, addr: u16, val: u8) {
+        self.bus.write8(addr, val);
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        self.bus.watchpoints()
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        self.bus.watchpoints_mut()
+    }
+
+    // The VDP's registers and memory, for debuggers
+    pub fn vdp_registers(&self) -> &[u8] {
+        self.bus.vdp().registers()
+    }
+
+    // The status register, without the side effects of reading it
+    pub fn vdp_status(&self) -> u8 {
+        self.bus.vdp().status()
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        self.bus.vdp().vram()
+    }
+
+    pub fn cram(&self) -> &[u8] {
+        self.bus.vdp().cram()
+    }
+
+    // The line the VDP is drawing, and the V counter as the CPU would read it
+    pub fn scanline(&self) -> u16 {
+        self.bus.vdp().scanline()
+    }
+
+    pub fn v_counter(&self) -> u8 {
+        self.bus.vdp().v_counter()
+    }
+
+    // Swaps the media in a slot while the system is running, returning what
+    // was there before
+    pub fn insert_media(&mut self, slot: Slot, media: Option<Cartridge>) -> Option<Cartridge> {
+        self.bus.insert(slot, media)
+    }
+
+    // Plugs a device into a controller port, replacing whatever was there
+    pub fn connect<D: ControllerPortDevice + 'static>(&mut self, port: Port, device: D) {
+        self.bus.connect(port, Box::new(device));
+    }
+
+    // Input is ignored while a movie plays back, the movie supplies it,
+    // until it runs out of frames. For movies to play back the same as they
+    // were recorded, input should only be given between frames.
+    pub fn set_input(&mut self, port: Port, input: &PortInput) {
+        if self.movie_status().is_some_and(|status| !status.recording && !status.finished()) {
+            return;
         }
+
+        self.inputs[port as usize] = *input;
+        self.bus.set_input(port, input);
     }
 
-    pub fn run(&mut self) {
-        loop {
-            self.cpu.step(&mut self.bus);
+    // Starts recording the input of every frame into a movie. A movie
+    // recorded straight after the VM is built starts at power on, otherwise
+    // it starts from a save state of the machine as it is now.
+    pub fn record_movie(&mut self) {
+        let start = if self.cpu.cycles() == 0 { None } else { Some(self.save_state()) };
+        let media = self.media_hashes();
+        let movie = Movie::new(self.system(), self.bus.region(), self.bus.vdp().tv_standard(), media, start);
+
+        self.movie = Some(MovieSession { movie, recording: true, frame: 0, desync: None });
+    }
+
+    // Plays a movie back, after checking it was recorded with the same
+    // media and settings. Playback starts straight away, from the movie's
+    // save state if it has one.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), Error> {
+        if movie.system != self.system() {
+            return Err(Error::MovieMismatch { setting: "system" });
+        }
+        if movie.region != self.bus.region() {
+            return Err(Error::MovieMismatch { setting: "region" });
+        }
+        if movie.tv_standard != self.bus.vdp().tv_standard() {
+            return Err(Error::MovieMismatch { setting: "TV standard" });
+        }
+
+        let names = ["BIOS", "cartridge", "card", "expansion"];
+        for (i, &media) in self.media_hashes().iter().enumerate() {
+            if movie.media[i] != media {
+                return Err(Error::MovieMismatch { setting: names[i] });
+            }
         }
+
+        match movie.start {
+            Some(ref state) => self.load_state(state)?,
+            None if self.cpu.cycles() != 0 => return Err(Error::MovieNeedsPowerOn),
+            None => {}
+        }
+
+        if let Some(&inputs) = movie.frames.first() {
+            self.apply_inputs(inputs);
+        }
+
+        self.movie = Some(MovieSession { movie, recording: false, frame: 0, desync: None });
+
+        Ok(())
+    }
+
+    // Stops recording or playing back, returning the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|session| session.movie)
+    }
+
+    pub fn movie_status(&self) -> Option<MovieStatus> {
+        self.movie.as_ref().map(|session| {
+            MovieStatus {
+                recording: session.recording,
+                frame: session.frame,
+                frames: session.movie.len(),
+                desync: session.desync,
+            }
+        })
+    }
+
+    fn media_hashes(&self) -> [Option<[u8; 20]>; 4] {
+        let mut hashes = [None; 4];
+
+        for (hash, &slot) in hashes.iter_mut().zip(SLOTS.iter()) {
+            *hash = self.bus.media(slot).map(|media| media.sha1());
+        }
+
+        hashes
+    }
+
+    // Records the frame just finished, or checks it matches the recording
+    // and moves on to the next frame's input
+    fn end_movie_frame(&mut self, session: &mut MovieSession) {
+        if session.recording {
+            session.movie.record(self.inputs, || self.save_state());
+            session.frame += 1;
+        } else if session.frame < session.movie.len() {
+            if session.desync.is_none() && !session.movie.check(session.frame, || self.save_state()) {
+                session.desync = Some(session.frame);
+            }
+
+            session.frame += 1;
+
+            if let Some(&inputs) = session.movie.frames.get(session.frame) {
+                self.apply_inputs(inputs);
+            }
+        }
+    }
+
+    fn apply_inputs(&mut self, inputs: [PortInput; 2]) {
+        self.bus.set_input(Port::A, &inputs[0]);
+        self.bus.set_input(Port::B, &inputs[1]);
+        self.inputs = inputs;
+    }
+
+    // Logs every instruction executed to the tracer, or stops logging with
+    // None. Returns the previous tracer.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        ::std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    // Keeps a snapshot at the end of every frame so rewind_frame can step
+    // back through them, or stops with None. Returns the previous buffer.
+    pub fn set_rewind(&mut self, rewind: Option<Rewind>) -> Option<Rewind> {
+        ::std::mem::replace(&mut self.rewind, rewind)
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    // Goes back a frame, for calling once per frame in place of run_frame
+    // while the rewind key is held. The snapshots only hold the machine, not
+    // its video output, so this restores the state from two frames back and
+    // runs the frame before the current one again, with the input it had
+    // the first time, to redraw it. The re-run is kept from the movie, the
+    // tracer and the watchpoints, which have all seen the frame already.
+    // Returns false once there's nothing older to go back to.
+    pub fn rewind_frame(&mut self) -> Result<bool, Error> {
+        let snapshot = match self.rewind {
+            Some(ref mut rewind) if rewind.len() >= 3 => {
+                rewind.pop();
+                let inputs = rewind.pop().map(|(_, inputs)| inputs);
+                rewind.last().and_then(|state| Some((state, inputs?)))
+            }
+            _ => None,
+        };
+
+        match snapshot {
+            Some((state, inputs)) => {
+                self.load_state(&state)?;
+                self.apply_inputs(inputs);
+
+                let movie = self.movie.take();
+                let tracer = self.tracer.take();
+                let watchpoints = mem::take(self.bus.watchpoints_mut());
+
+                let result = self.run_frame().map(|_| ());
+
+                self.movie = movie;
+                self.tracer = tracer;
+                *self.bus.watchpoints_mut() = watchpoints;
+
+                result.map(|_| true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Serialises the whole machine. The media isn't included, loading the
+    // state needs the same media in the same slots.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.header();
+        w.chunk(b"INFO", |w| {
+            for &slot in &SLOTS {
+                w.u32(self.bus.media(slot).map_or(0, |media| media.crc32()));
+            }
+        });
+        w.chunk(b"CPU ", |w| self.cpu.save_state(w));
+        self.bus.save_state(&mut w);
+
+        w.into_bytes()
+    }
+
+    // Converts the current state to another emulator's format
+    pub fn export_state(&self, format: StateFormat) -> Result<Vec<u8>, Error> {
+        let state = self.save_state();
+
+        match format {
+            StateFormat::Caduceus => Ok(state),
+            StateFormat::Meka => savestate::export_meka(&state).map_err(|reason| Error::InvalidState { reason }),
+            _ => Err(Error::UnsupportedStateFormat { format }),
+        }
+    }
+
+    // Restores a state from save_state, or from another emulator in one of
+    // the formats that can be imported. The state is checked before anything
+    // is touched, so on an error the VM carries on as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        match StateFormat::detect(data) {
+            Some(StateFormat::Caduceus) => self.load_native_state(data),
+            Some(StateFormat::Meka) => {
+                let state = savestate::import_meka(data).map_err(|reason| Error::InvalidState { reason })?;
+                self.load_native_state(&state)
+            }
+            Some(format) => Err(Error::UnsupportedStateFormat { format }),
+            None => Err(Error::InvalidState { reason: "not a save state caduceus recognises" }),
+        }
+    }
+
+    fn load_native_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut chunks = savestate::chunks(data).map_err(|reason| Error::InvalidState { reason })?;
+
+        match chunks.iter_mut().find(|&&mut (ref tag, _)| tag == b"INFO") {
+            Some(&mut (_, ref mut info)) => {
+                for &slot in &SLOTS {
+                    if info.u32() != self.bus.media(slot).map_or(0, |media| media.crc32()) {
+                        return Err(Error::StateMediaMismatch { slot });
+                    }
+                }
+            }
+            None => return Err(Error::InvalidState { reason: "no media information" }),
+        }
+
+        for (tag, mut chunk) in chunks {
+            if &tag == b"CPU " {
+                self.cpu.load_state(&mut chunk);
+            } else {
+                self.bus.load_chunk(&tag, &mut chunk);
+            }
+        }
+
+        self.frame_ready = false;
+
+        Ok(())
+    }
+
+    // Executes a single instruction, returning the number of T-states it
+    // took. On a fault the VM is left as it was just before the instruction.
+    pub fn step_instruction(&mut self) -> Result<u32, Error> {
+        if let Some(ref mut tracer) = self.tracer {
+            if self.cpu.executes_instruction(&self.bus) {
+                tracer.trace(self.cpu.state(), &self.bus)
+                    .map_err(|source| Error::Trace { source })?;
+            }
+        }
+
+        let cycles = self.cpu.step(&mut self.bus)?;
+        self.bus.advance_to(self.cpu.cycles());
+
+        if self.bus.vdp_mut().take_frame_complete() {
+            let eye = self.bus.shutter();
+            self.glasses.capture(self.bus.vdp().framebuffer(), eye);
+            self.frame_ready = true;
+
+            // The movie moves the input on to the next frame's
+            let inputs = self.inputs;
+
+            if let Some(mut session) = self.movie.take() {
+                self.end_movie_frame(&mut session);
+                self.movie = Some(session);
+            }
+
+            if let Some(mut rewind) = self.rewind.take() {
+                rewind.push(&self.save_state(), inputs);
+                self.rewind = Some(rewind);
+            }
+        }
+
+        Ok(cycles)
+    }
+
+    // Executes whole instructions until at least the given number of
+    // T-states have passed, returning how many actually did
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<u64, Error> {
+        let mut elapsed = 0;
+
+        while elapsed < cycles {
+            elapsed += self.step_instruction()? as u64;
+        }
+
+        Ok(elapsed)
+    }
+
+    // Runs until the VDP finishes the next frame
+    pub fn run_frame(&mut self) -> Result<Frame<'_>, Error> {
+        self.clear_audio();
+        self.frame_ready = false;
+
+        while !self.frame_ready {
+            self.step_instruction()?;
+        }
+
+        Ok(Frame {
+            video: self.glasses.output(),
+            width: self.glasses.width(),
+            audio: self.bus.psg().samples(),
+        })
+    }
+}
+
+// Memory as the CPU currently sees it, for disassembling and symbols
+impl Memory for VM {
+    fn peek8(&self, addr: u16) -> u8 {
+        self.bus.peek8(addr)
+    }
+
+    fn bank(&self, addr: u16) -> Option<usize> {
+        self.bus.bank(addr)
     }
 }
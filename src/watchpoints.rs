@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    // Data reads, opcode and operand fetches don't count
+    Read,
+    Write,
+    In,
+    Out,
+}
+
+// An access that matched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    pub access: Access,
+    // Memory address, or port number for In and Out
+    pub addr: u16,
+    pub val: u8,
+}
+
+// Memory and port accesses by the CPU to stop on. Reads made by debuggers
+// and the disassembler don't go through here.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    reads: HashSet<u16>,
+    writes: HashSet<u16>,
+    inputs: HashSet<u8>,
+    outputs: HashSet<u8>,
+    hit: Option<Hit>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, access: Access, addr: u16) {
+        match access {
+            Access::Read => self.reads.insert(addr),
+            Access::Write => self.writes.insert(addr),
+            Access::In => self.inputs.insert(addr as u8),
+            Access::Out => self.outputs.insert(addr as u8),
+        };
+    }
+
+    pub fn remove(&mut self, access: Access, addr: u16) {
+        match access {
+            Access::Read => self.reads.remove(&addr),
+            Access::Write => self.writes.remove(&addr),
+            Access::In => self.inputs.remove(&(addr as u8)),
+            Access::Out => self.outputs.remove(&(addr as u8)),
+        };
+    }
+
+    // Every watchpoint, memory then ports
+    pub fn list(&self) -> Vec<(Access, u16)> {
+        let mut list = Vec::new();
+
+        list.extend(self.reads.iter().map(|&addr| (Access::Read, addr)));
+        list.extend(self.writes.iter().map(|&addr| (Access::Write, addr)));
+        list.extend(self.inputs.iter().map(|&port| (Access::In, port as u16)));
+        list.extend(self.outputs.iter().map(|&port| (Access::Out, port as u16)));
+        list.sort_by_key(|&(access, addr)| (access as u8, addr));
+
+        list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty() &&
+            self.inputs.is_empty() && self.outputs.is_empty()
+    }
+
+    // The first access to match since the last call
+    pub fn take_hit(&mut self) -> Option<Hit> {
+        self.hit.take()
+    }
+
+    // Called by the bus on every CPU access. Ports only match on the low
+    // byte, which is all the SMS decodes.
+    pub fn check(&mut self, access: Access, addr: u16, val: u8) {
+        if self.hit.is_some() || self.is_empty() {
+            return;
+        }
+
+        let matched = match access {
+            Access::Read => self.reads.contains(&addr),
+            Access::Write => self.writes.contains(&addr),
+            Access::In => self.inputs.contains(&(addr as u8)),
+            Access::Out => self.outputs.contains(&(addr as u8)),
+        };
+
+        if matched {
+            self.hit = Some(Hit { access, addr, val });
+        }
+    }
+}